@@ -0,0 +1,636 @@
+use crate::chat::{
+    ChatBackend,
+    ChatOptions,
+    ChatResult,
+    ChatSession,
+    ChatStream,
+    ChatContent,
+    ChatContentPart,
+    ChatRole,
+    ChatError,
+    OutputFormat,
+    build_messages,
+    content_stream,
+    parse_stream_chunk,
+    strip_prefix_ai
+};
+use async_recursion::async_recursion;
+use serde::{Serialize,Deserialize};
+use reqwest::{Client,RequestBuilder};
+use reqwest_eventsource::{EventSource,Event};
+use clap::ValueEnum;
+use serde_json::{json,Value};
+use std::time::Instant;
+use futures_util::future::BoxFuture;
+use futures_util::stream::StreamExt;
+use crate::anthropic::{self,AnthropicError};
+use crate::completion::ClashingArgumentsError;
+use crate::session::{Model,ModelFocus};
+use crate::pricing::cost_summary;
+use crate::{cache,request,Config};
+
+pub struct AnthropicChatCommand {
+    options: ChatOptions
+}
+
+impl ChatBackend for AnthropicChatCommand {
+    fn run<'a>(&'a mut self, client: &'a Client, config: &'a Config) -> BoxFuture<'a, ChatResult> {
+        AnthropicChatCommand::run(self, client, config)
+    }
+
+    fn stream<'a>(&'a self, client: &'a Client, config: &'a Config)
+        -> BoxFuture<'a, Result<ChatStream, ChatError>>
+    {
+        Box::pin(async move {
+            let options = &self.options;
+            let body = get_request_body(options, true)?;
+            let request = get_request(client, options, config, &body)?;
+            request::throttle(&request, config).await;
+
+            let prefix_ai = (!options.raw).then(|| options.prefix_ai.clone());
+            Ok(content_stream(EventSource::new(request)?, prefix_ai, parse_stream_content))
+        })
+    }
+
+    fn session(&self) -> Result<ChatSession, ChatError> {
+        ChatSession::try_from(&self.options)
+    }
+}
+
+impl TryFrom<ChatOptions> for AnthropicChatCommand {
+    type Error = ChatError;
+
+    fn try_from(options: ChatOptions) -> Result<Self, Self::Error> {
+        if !(0.0..=1.0).contains(&options.temperature) {
+            return Err(ChatError::TemperatureOutOfValidRange);
+        }
+
+        let unsupported = [
+            (!options.tools.is_empty(), "Tools aren't supported by Anthropic yet"),
+            (options.logprobs, "Anthropic doesn't return logprobs"),
+            (options.json_mode, "Anthropic doesn't support JSON mode"),
+            (options.json_schema.is_some(), "Anthropic doesn't support JSON schemas"),
+            (options.presence_penalty.is_some(), "Anthropic doesn't support a presence penalty"),
+            (options.frequency_penalty.is_some(), "Anthropic doesn't support a frequency penalty"),
+            (options.completion.seed.is_some(), "Anthropic doesn't support seeded sampling"),
+            (!options.logit_bias.is_empty(), "Anthropic doesn't support a logit bias"),
+            (options.completion.response_count.is_some_and(|count| count != 1),
+                "Anthropic only generates a single response per request"),
+        ];
+        if let Some((_, error)) = unsupported.iter().find(|(used, _)| *used) {
+            return Err(ChatError::ClashingArguments(ClashingArgumentsError::new(error)));
+        }
+
+        Ok(AnthropicChatCommand { options })
+    }
+}
+
+impl AnthropicChatCommand {
+    #[async_recursion]
+    pub async fn run(&mut self, client: &Client, config: &Config) -> ChatResult {
+        let options = &mut self.options;
+        let print_output = !options.completion.quiet.unwrap_or(false);
+        let stream = options.stream;
+
+        if options.completion.dry_run.unwrap_or(false) {
+            println!("{}", serde_json::to_string_pretty(&get_request_body(options, stream)?)?);
+            return Ok(vec![]);
+        }
+
+        loop {
+            let result = if stream {
+                handle_stream(client, options, config).await?
+            } else {
+                handle_sync(client, options, config, print_output).await?
+            };
+            if !result.is_empty() {
+                return Ok(result);
+            }
+
+            if options.file.read(None, Some(&*options.prefix_user), options.no_context).is_none() {
+                return Ok(vec![]);
+            }
+        }
+    }
+}
+
+async fn handle_sync(client: &Client, options: &mut ChatOptions, config: &Config, print_output: bool) -> ChatResult {
+    let body = get_request_body(options, false)?;
+    let request = get_request(client, options, config, &body)?;
+    let response = match cache::get(config, &request, &body) {
+        Some(response) => response,
+        None => {
+            let cache_request = request.try_clone();
+            let request = request::send(request, config)
+                .await
+                .map_err(ChatError::from_request_error)?;
+
+            log::debug!("Anthropic responded with {}", request.status());
+            if !request.status().is_success() {
+                return Err(ChatError::AnthropicError(request.json().await?));
+            }
+
+            let response = request.text().await?;
+            if let Some(cache_request) = cache_request {
+                cache::put(config, &cache_request, &body, &response);
+            }
+            response
+        }
+    };
+
+    let response: AnthropicMessageResponse = serde_json::from_str(&response)?;
+    log::trace!("Chat response: {:?}", response);
+    if let (true, Some(usage)) = (options.completion.show_usage.unwrap_or(false), &response.usage) {
+        eprintln!("{}", usage);
+    }
+    if let (true, Some(usage)) = (options.completion.show_cost.unwrap_or(false), &response.usage) {
+        eprintln!("{}", usage.cost_summary(&response.model));
+    }
+    if options.completion.show_response_info.unwrap_or(false) {
+        eprintln!("{}", response.info());
+    }
+
+    if let Some("max_tokens") = response.stop_reason.as_deref() {
+        eprintln!(concat!(
+            "warning: The response was cut off because it reached the maximum number of tokens. ",
+            "Raise the token limit or ask the AI to continue."));
+    }
+
+    let content = options.response_content(&response.text());
+    let text = options.write_response(&content)?;
+
+    if print_output {
+        match options.format {
+            OutputFormat::Text => options.stream_handler.handle(&format!("{}\n", text))?,
+            OutputFormat::Json => options.stream_handler.handle(&format!("{}\n", json!({
+                "id": response.id,
+                "model": response.model,
+                "role": ChatRole::Ai,
+                "content": content.trim(),
+                "finish_reason": response.stop_reason,
+                "usage": response.usage
+            })))?
+        }
+    }
+
+    options.response_result(content, response.stop_reason)
+}
+
+async fn handle_stream(client: &Client, options: &mut ChatOptions, config: &Config) -> ChatResult {
+    let transcript_len = options.file.transcript.len();
+    let result = stream_response(client, options, config).await;
+    // Writing the response to the transcript replaces the partial response saved while streaming,
+    // every way out that doesn't write it has to take the partial response back out
+    if options.file.transcript.len() == transcript_len {
+        options.discard_partial_response();
+    }
+    result
+}
+
+async fn stream_response(client: &Client, options: &mut ChatOptions, config: &Config) -> ChatResult {
+    let body = get_request_body(options, true)?;
+    let request = get_request(client, options, config, &body)?;
+    request::throttle(&request, config).await;
+    let mut stream = EventSource::new(request)?;
+    let mut response = String::new();
+    let mut has_written_content = false;
+    // The prompt tokens arrive when the message starts, the completion tokens when it ends
+    let mut model = String::new();
+    let mut prompt_tokens = 0;
+    let mut persisted_at = Instant::now();
+
+    'stream: loop {
+        let event = tokio::select! {
+            event = stream.next() => event,
+            _ = options.cancellation.cancelled() => {
+                stream.close();
+                break 'stream;
+            }
+        };
+        let Some(event) = event else {
+            break 'stream;
+        };
+
+        let message = match event {
+            Ok(Event::Open) => {
+                log::debug!("Opened the Anthropic chat stream");
+                continue;
+            },
+            Ok(Event::Message(message)) => message,
+            Err(reqwest_eventsource::Error::StreamEnded) => break 'stream,
+            Err(reqwest_eventsource::Error::Transport(err)) if err.is_timeout() => {
+                stream.close();
+                return Err(ChatError::stream_interrupted(options, response, ChatError::Timeout));
+            },
+            Err(err) => {
+                stream.close();
+                let error = ChatError::EventSource(Box::new(err));
+                return Err(ChatError::stream_interrupted(options, response, error));
+            }
+        };
+
+        log::trace!("Stream message: {}", message.data);
+        let event = match read_stream_event(&message.data) {
+            Ok(Some(event)) => event,
+            Ok(None) => continue,
+            Err(error) => {
+                stream.close();
+                return Err(ChatError::stream_interrupted(options, response, error));
+            }
+        };
+        match event {
+            AnthropicStreamEvent::MessageStart { message } => {
+                if !options.raw {
+                    let role = options.ai_label();
+                    options.stream_handler.handle(&role)?;
+                    response.push_str(&role);
+                }
+                prompt_tokens = message.usage.as_ref().map_or(0, |usage| usage.input_tokens);
+                model = message.model.clone();
+                print_stream_usage(options, message.usage);
+                if options.completion.show_response_info.unwrap_or(false) {
+                    eprintln!("response: {} from the {} model", message.id, message.model);
+                }
+            },
+            AnthropicStreamEvent::ContentBlockDelta { delta: AnthropicDelta::TextDelta { text } } => {
+                let text = match has_written_content {
+                    _ if options.raw => text,
+                    false => {
+                        let text = text.trim_start();
+                        strip_prefix_ai(text, &options.prefix_ai).unwrap_or(text).to_string()
+                    },
+                    true => text
+                };
+
+                options.stream_handler.handle(&text)?;
+                response.push_str(&text);
+                has_written_content = has_written_content || !text.is_empty();
+                options.persist_partial_response(&response, &mut persisted_at);
+            },
+            AnthropicStreamEvent::MessageDelta { usage } => {
+                let completion_tokens = usage.as_ref().map(|usage| usage.output_tokens);
+                print_stream_usage(options, usage);
+                if let (true, Some(completion_tokens)) =
+                    (options.completion.show_cost.unwrap_or(false), completion_tokens)
+                {
+                    eprintln!("{}", cost_summary(&model, prompt_tokens, completion_tokens));
+                }
+            },
+            AnthropicStreamEvent::MessageStop => {
+                stream.close();
+                break 'stream;
+            },
+            AnthropicStreamEvent::Error { error } => {
+                stream.close();
+                let error = ChatError::AnthropicError(AnthropicError { error });
+                return Err(ChatError::stream_interrupted(options, response, error));
+            },
+            AnthropicStreamEvent::ContentBlockDelta { .. } |
+            AnthropicStreamEvent::Other => {}
+        }
+    }
+
+    if !response.is_empty() {
+        options.stream_handler.handle("\n")?;
+        response += "\n";
+    }
+
+    let content = response
+        .strip_prefix(&options.ai_label())
+        .unwrap_or(&response)
+        .trim_end()
+        .to_string();
+    options.write_response(&content)?;
+
+    options.response_result(content, None)
+}
+
+/// Reads a streamed event, None for an event that isn't JSON
+fn read_stream_event(data: &str) -> Result<Option<AnthropicStreamEvent>, ChatError> {
+    match parse_stream_chunk(data, "Anthropic chat") {
+        Some(event) => Ok(Some(serde_json::from_value(event)?)),
+        None => Ok(None)
+    }
+}
+
+fn parse_stream_content(data: &str) -> Result<Option<String>, ChatError> {
+    let Some(event) = read_stream_event(data)? else {
+        return Ok(Some(String::new()));
+    };
+    match event {
+        AnthropicStreamEvent::ContentBlockDelta { delta: AnthropicDelta::TextDelta { text } } => {
+            Ok(Some(text))
+        },
+        AnthropicStreamEvent::MessageStop => Ok(None),
+        AnthropicStreamEvent::Error { error } => Err(ChatError::AnthropicError(AnthropicError { error })),
+        _ => Ok(Some(String::new()))
+    }
+}
+
+fn print_stream_usage(options: &ChatOptions, usage: Option<AnthropicUsage>) {
+    if let (true, Some(usage)) = (options.completion.show_usage.unwrap_or(false), usage) {
+        eprintln!("{}", usage);
+    }
+}
+
+fn get_request(
+    client: &Client,
+    options: &ChatOptions,
+    config: &Config,
+    body: &Value) -> Result<RequestBuilder, ChatError>
+{
+    let request = anthropic::post(client, config, options.completion.api_key.as_deref())?
+        .ok_or(ChatError::Unauthorized)?
+        .json(body);
+
+    log::debug!("Requesting an Anthropic message from {}", body["model"]);
+    log::trace!("Request body: {}", body);
+
+    Ok(match config.timeout() {
+        Some(timeout) => request.timeout(timeout),
+        None => request
+    })
+}
+
+fn get_request_body(options: &ChatOptions, stream: bool) -> Result<Value, ChatError> {
+    let mut system = vec![];
+    let mut messages: Vec<AnthropicMessage> = vec![];
+
+    // Anthropic takes the system prompt separately and expects the conversation to alternate
+    // between the user and the assistant, so consecutive messages of a role are merged
+    for message in build_messages(options)? {
+        let role = match message.role {
+            ChatRole::System => {
+                system.push(message.content.text());
+                continue;
+            },
+            ChatRole::Ai => "assistant",
+            ChatRole::User | ChatRole::Tool => "user"
+        };
+
+        let content = content_blocks(&message.content);
+        match messages.last_mut() {
+            Some(last) if last.role == role => last.content.extend(content),
+            _ => messages.push(AnthropicMessage { role, content })
+        }
+    }
+
+    let response_tokens = options.tokens_max
+        - (options.tokens_max as f32 * options.tokens_balance).floor() as usize;
+    let mut body = json!({
+        "model": options.anthropic_model.to_versioned(),
+        "max_tokens": options.max_tokens.unwrap_or(response_tokens).max(1),
+        "temperature": options.temperature,
+        "messages": messages,
+        "stream": stream
+    });
+
+    if !system.is_empty() {
+        body["system"] = json!(system.join("\n\n"));
+    }
+
+    if let Some(top_p) = options.completion.top_p {
+        body["top_p"] = json!(top_p);
+    }
+
+    if let Some(stop) = &options.completion.stop {
+        body["stop_sequences"] = json!(stop);
+    }
+
+    if let Some(user) = &options.user {
+        body["metadata"] = json!({ "user_id": user });
+    }
+
+    Ok(body)
+}
+
+/// Maps message content to Anthropic content blocks. Anthropic rejects empty text blocks, and
+/// takes base64 data URLs as a separate image source type.
+fn content_blocks(content: &ChatContent) -> Vec<Value> {
+    let parts = match content {
+        ChatContent::Text(text) => vec![ChatContentPart::Text { text: text.clone() }],
+        ChatContent::Parts(parts) => parts.clone()
+    };
+
+    parts.into_iter()
+        .filter_map(|part| match part {
+            ChatContentPart::Text { text } if text.trim().is_empty() => None,
+            ChatContentPart::Text { text } => Some(json!({ "type": "text", "text": text })),
+            ChatContentPart::ImageUrl { image_url } => {
+                let source = image_url.url
+                    .strip_prefix("data:")
+                    .and_then(|data| data.split_once(";base64,"))
+                    .map(|(media_type, data)| json!({
+                        "type": "base64",
+                        "media_type": media_type,
+                        "data": data
+                    }))
+                    .unwrap_or_else(|| json!({ "type": "url", "url": image_url.url }));
+
+                Some(json!({ "type": "image", "source": source }))
+            }
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct AnthropicMessage {
+    role: &'static str,
+    content: Vec<Value>
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum AnthropicChatModel {
+    /// Anthropic's most capable model
+    #[value(name = "claude-3-opus")]
+    #[serde(rename = "claude-3-opus")]
+    Claude3Opus,
+
+    /// Anthropic's balanced model of intelligence and speed
+    #[default]
+    #[value(name = "claude-3-5-sonnet")]
+    #[serde(rename = "claude-3-5-sonnet")]
+    Claude35Sonnet,
+
+    /// Anthropic's fastest and cheapest model
+    #[value(name = "claude-3-haiku")]
+    #[serde(rename = "claude-3-haiku")]
+    Claude3Haiku,
+}
+
+impl TryFrom<(ModelFocus, Model)> for AnthropicChatModel {
+    type Error = ChatError;
+
+    fn try_from(models: (ModelFocus, Model)) -> Result<AnthropicChatModel, ChatError> {
+        Ok(match models {
+            (ModelFocus::Code, Model::Tiny) |
+            (ModelFocus::Code, Model::Small) => return Err(ChatError::NoMatchingModel),
+            (ModelFocus::Text, Model::Tiny) => {
+                eprintln!(concat!(
+                    "warning: No exact matching Anthropic chat model for the tiny option. ",
+                    "Falling back to claude-3-haiku."));
+                AnthropicChatModel::Claude3Haiku
+            },
+            (_, Model::Small) |
+            (_, Model::Medium) => AnthropicChatModel::Claude3Haiku,
+            (_, Model::Large) |
+            (_, Model::XLarge) => AnthropicChatModel::Claude35Sonnet,
+            (_, Model::XXLarge) => AnthropicChatModel::Claude3Opus,
+        })
+    }
+}
+
+impl AnthropicChatModel {
+    pub fn to_versioned(&self) -> &str {
+        match self {
+            AnthropicChatModel::Claude3Opus => "claude-3-opus-20240229",
+            AnthropicChatModel::Claude35Sonnet => "claude-3-5-sonnet-20240620",
+            AnthropicChatModel::Claude3Haiku => "claude-3-haiku-20240307",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AnthropicMessageResponse {
+    pub id: String,
+    pub model: String,
+    pub content: Vec<AnthropicContentBlock>,
+    pub stop_reason: Option<String>,
+    pub usage: Option<AnthropicUsage>
+}
+
+impl AnthropicMessageResponse {
+    /// The response id and the model that generated it, for logging
+    pub fn info(&self) -> String {
+        format!("response: {} from the {} model", self.id, self.model)
+    }
+
+    /// The text blocks of the response joined together
+    pub fn text(&self) -> String {
+        self.content
+            .iter()
+            .filter_map(|block| match block {
+                AnthropicContentBlock::Text { text } => Some(&**text),
+                AnthropicContentBlock::Other => None
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicContentBlock {
+    Text { text: String },
+    #[serde(other)]
+    Other
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct AnthropicUsage {
+    #[serde(default)]
+    pub input_tokens: usize,
+    #[serde(default)]
+    pub output_tokens: usize
+}
+
+impl AnthropicUsage {
+    /// The estimated cost of the request in dollars, None when the model has no known price
+    pub fn cost(&self, model: &str) -> Option<f64> {
+        crate::pricing::estimate_cost(model, self.input_tokens, self.output_tokens)
+    }
+
+    pub(crate) fn cost_summary(&self, model: &str) -> String {
+        cost_summary(model, self.input_tokens, self.output_tokens)
+    }
+}
+
+impl std::fmt::Display for AnthropicUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "usage: {} prompt tokens, {} completion tokens, {} total tokens",
+            self.input_tokens, self.output_tokens, self.input_tokens + self.output_tokens)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicStreamEvent {
+    MessageStart { message: AnthropicStreamMessage },
+    ContentBlockDelta { delta: AnthropicDelta },
+    MessageDelta { usage: Option<AnthropicUsage> },
+    MessageStop,
+    Error { error: crate::anthropic::error::AnthropicErrorInner },
+    #[serde(other)]
+    Other
+}
+
+#[derive(Deserialize)]
+struct AnthropicStreamMessage {
+    id: String,
+    model: String,
+    usage: Option<AnthropicUsage>
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum AnthropicDelta {
+    TextDelta { text: String },
+    #[serde(other)]
+    Other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::*;
+    use crate::completion::*;
+
+    #[test]
+    fn request_body_takes_the_system_prompt_out_of_the_messages() {
+        let file = CompletionFile {
+            path: None,
+            header: String::new(),
+            overrides: ChatCommand::default(),
+            last_read_input: String::new(),
+            last_written_input: String::new(),
+            transcript: concat!(
+                "USER: hey\n",
+                "TOOL: quack\n",
+                "AI: hello"
+            ).to_string()
+        };
+        let options = ChatOptions {
+            tokens_max: 4096,
+            tokens_balance: 0.5,
+            system: "You're a duck.".into(),
+            file,
+            ..ChatOptions::default()
+        };
+        let body = get_request_body(&options, false).unwrap();
+
+        assert_eq!(body["system"], json!("You're a duck."));
+        assert_eq!(body["max_tokens"], json!(2048));
+        assert_eq!(body["messages"], json!([
+            {
+                "role": "user",
+                "content": [{ "type": "text", "text": "hey" }, { "type": "text", "text": "quack" }]
+            },
+            { "role": "assistant", "content": [{ "type": "text", "text": "hello" }] }
+        ]));
+    }
+
+    #[test]
+    fn stream_events_that_arent_json_are_skipped() {
+        assert_eq!(Some(String::new()), parse_stream_content("{\"type\": \"content_block_delta\"").unwrap());
+        assert_eq!(
+            Some(String::from("Quack")),
+            parse_stream_content(r#"{"type": "content_block_delta", "delta": {"type": "text_delta", "text": "Quack"}}"#).unwrap());
+        assert_eq!(None, parse_stream_content(r#"{"type": "message_stop"}"#).unwrap());
+    }
+
+    #[test]
+    fn stream_error_events_end_the_stream() {
+        let error = parse_stream_content(
+            r#"{"type": "error", "error": {"type": "overloaded_error", "message": "Overloaded"}}"#);
+        assert!(matches!(error, Err(ChatError::AnthropicError(_))));
+    }
+}
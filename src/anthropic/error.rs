@@ -0,0 +1,26 @@
+use serde::Deserialize;
+
+/// The error body returned by Anthropic, `{ "type": "error", "error": { "type", "message" } }`
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnthropicError {
+    pub error: AnthropicErrorInner
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnthropicErrorInner {
+    #[serde(default)]
+    pub r#type: String,
+    pub message: String
+}
+
+impl std::fmt::Display for AnthropicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let AnthropicErrorInner { r#type, message } = &self.error;
+
+        write!(f, "Anthropic responded with an error: {}", message)?;
+        if !r#type.is_empty() {
+            write!(f, " ({})", r#type)?;
+        }
+        Ok(())
+    }
+}
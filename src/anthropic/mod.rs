@@ -0,0 +1,70 @@
+use std::env;
+use reqwest::{Client,Method,RequestBuilder,StatusCode};
+use crate::chat::ChatError;
+use crate::config::{self,Config,InvalidApiKeyError};
+use crate::request;
+
+pub mod chat;
+pub mod error;
+
+pub use chat::AnthropicChatCommand;
+pub use error::AnthropicError;
+
+pub const ANTHROPIC_BASE_URL: &str = "https://api.anthropic.com";
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+/// Starts an authenticated POST request to the Anthropic messages endpoint. An explicitly passed
+/// API key takes precedence over the environment and then the key in the config. Returns None
+/// when no API key could be found, and an error when the key found is malformed.
+pub(crate) fn post(
+    client: &Client,
+    config: &Config,
+    api_key: Option<&str>) -> Result<Option<RequestBuilder>, InvalidApiKeyError>
+{
+    authorized_request(client, config, api_key, Method::POST, "messages")
+}
+
+/// Checks the API key and the connection to Anthropic by listing the models, which doesn't
+/// generate anything. A rejected key is returned as ApiKeyRejected, and network failures as
+/// Request or Timeout errors
+pub(crate) async fn verify(
+    client: &Client,
+    config: &Config,
+    api_key: Option<&str>) -> Result<(), ChatError>
+{
+    let request = authorized_request(client, config, api_key, Method::GET, "models")?
+        .ok_or(ChatError::Unauthorized)?;
+    let response = request::send(request, config)
+        .await
+        .map_err(ChatError::from_request_error)?;
+
+    log::debug!("Anthropic responded to the models request with {}", response.status());
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        StatusCode::UNAUTHORIZED => Err(ChatError::ApiKeyRejected),
+        _ => Err(ChatError::AnthropicError(response.json().await?))
+    }
+}
+
+fn authorized_request(
+    client: &Client,
+    config: &Config,
+    api_key: Option<&str>,
+    method: Method,
+    endpoint: &str) -> Result<Option<RequestBuilder>, InvalidApiKeyError>
+{
+    let key = api_key
+        .map(String::from)
+        .or_else(|| env::var("ANTHROPIC_API_KEY").ok())
+        .or_else(|| config.api_key_anthropic.clone());
+    let Some(key) = key else {
+        return Ok(None);
+    };
+    let key = config::check_api_key(&key, Some("sk-ant-"))?;
+
+    let request = client.request(method, format!("{}/v1/{}", ANTHROPIC_BASE_URL, endpoint))
+        .header("x-api-key", key)
+        .header("anthropic-version", ANTHROPIC_VERSION);
+
+    Ok(Some(request::prepare(request, config)))
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::hash::Hasher;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use fnv::FnvHasher;
+use reqwest::RequestBuilder;
+use serde::{Serialize,Deserialize};
+use serde_json::Value;
+use crate::Config;
+
+/// The headers providers take the API key in. Only these are part of the cache key, other headers
+/// like the ones middleware adds can change from request to request
+const API_KEY_HEADERS: &[&str] = &["authorization", "api-key", "x-api-key"];
+
+/// What a cached response was requested with. It's stored next to the response and compared on
+/// read, so two requests whose hashes collide don't get each others response
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct CachedRequest {
+    /// The URL the request was sent to, covering the base URL, the Azure deployment and the
+    /// endpoint
+    url: String,
+    /// A hash of the API key, so responses aren't shared between accounts without storing the key
+    api_key: String,
+    body: Value
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedResponse {
+    request: CachedRequest,
+    response: String
+}
+
+/// Looks up the cached response body for a request, None when caching is disabled, the request
+/// isn't cacheable or the cached response is older than the cache TTL.
+pub(crate) fn get(config: &Config, request: &RequestBuilder, body: &Value) -> Option<String> {
+    let (path, cached_request) = path(config, request, body)?;
+    let age = fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())?;
+
+    if age > config.cache_ttl()? {
+        return None;
+    }
+
+    let cached: CachedResponse = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+    if cached.request != cached_request {
+        log::debug!("Ignoring the cached response in {} for a different request", path.display());
+        return None;
+    }
+
+    log::debug!("Using the cached response in {}", path.display());
+    Some(cached.response)
+}
+
+/// Saves the response body of a successful request. A response that can't be cached is only
+/// logged, since the request itself succeeded.
+pub(crate) fn put(config: &Config, request: &RequestBuilder, body: &Value, response: &str) {
+    let Some((path, request)) = path(config, request, body) else {
+        return;
+    };
+
+    let cached = CachedResponse { request, response: response.to_string() };
+    let written = path.parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, serde_json::to_string(&cached)?));
+    if let Err(error) = written {
+        log::warn!("Couldn't cache the response in {}: {}", path.display(), error);
+    }
+}
+
+/// Sampled responses are only reproducible with a seed, so requests are only cached when the
+/// temperature is 0 or a seed is set
+fn is_cacheable(body: &Value) -> bool {
+    let deterministic = body["temperature"].as_f64().is_some_and(|temperature| temperature <= 0.0);
+    let stream = body["stream"].as_bool().unwrap_or(false);
+
+    !stream && (deterministic || !body["seed"].is_null())
+}
+
+fn path(config: &Config, request: &RequestBuilder, body: &Value)
+    -> Option<(PathBuf, CachedRequest)>
+{
+    config.cache_ttl()?;
+    if !is_cacheable(body) {
+        return None;
+    }
+
+    let request = request.try_clone()?.build().ok()?;
+    let mut api_key = FnvHasher::default();
+    for name in API_KEY_HEADERS {
+        if let Some(value) = request.headers().get(*name) {
+            api_key.write(value.as_bytes());
+        }
+    }
+    let request = CachedRequest {
+        url: request.url().to_string(),
+        api_key: format!("{:016x}", api_key.finish()),
+        body: body.clone()
+    };
+
+    let mut hasher = FnvHasher::default();
+    hasher.write(request.url.as_bytes());
+    hasher.write(request.api_key.as_bytes());
+    hasher.write(request.body.to_string().as_bytes());
+
+    let mut path = config.dir.clone();
+    path.push("cache");
+    path.push(format!("{:016x}.json", hasher.finish()));
+    Some((path, request))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::Client;
+    use serde_json::json;
+
+    fn config(name: &str) -> Config {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("ai-cli-cache-test-{}-{}", name, std::process::id()));
+
+        Config {
+            cache_ttl_secs: Some(60),
+            dir,
+            ..Config::default()
+        }
+    }
+
+    fn request(url: &str, api_key: &str, body: &Value) -> RequestBuilder {
+        Client::new().post(url).bearer_auth(api_key).json(body)
+    }
+
+    #[test]
+    fn responses_are_cached_per_url_and_api_key() {
+        let config = config("keys");
+        let body = json!({ "model": "gpt-4o", "temperature": 0.0 });
+        let url = "https://api.openai.com/v1/chat/completions";
+        put(&config, &request(url, "sk-duck", &body), &body, "quack");
+
+        assert_eq!(Some("quack"), get(&config, &request(url, "sk-duck", &body), &body).as_deref());
+        assert_eq!(None, get(&config, &request(url, "sk-goose", &body), &body));
+        assert_eq!(None, get(&config, &request("http://localhost:8080/v1/chat/completions", "sk-duck", &body), &body));
+        assert_eq!(None, get(&config, &request(
+            "https://ducks.openai.azure.com/openai/deployments/gpt-4o-mini/chat/completions",
+            "sk-duck",
+            &body), &body));
+
+        let other_body = json!({ "model": "gpt-4o-mini", "temperature": 0.0 });
+        assert_eq!(None, get(&config, &request(url, "sk-duck", &other_body), &other_body));
+    }
+
+    #[test]
+    fn cached_responses_for_a_different_request_are_ignored() {
+        let config = config("collisions");
+        let url = "https://api.openai.com/v1/chat/completions";
+        let body = json!({ "model": "gpt-4o", "temperature": 0.0 });
+        let other_body = json!({ "model": "gpt-4o", "seed": 1 });
+        put(&config, &request(url, "sk-duck", &other_body), &other_body, "moo");
+
+        // Pretend the other request's hash collides with this one's
+        let (other_path, _) = path(&config, &request(url, "sk-duck", &other_body), &other_body).unwrap();
+        let (path, _) = path(&config, &request(url, "sk-duck", &body), &body).unwrap();
+        fs::copy(other_path, path).unwrap();
+
+        assert_eq!(None, get(&config, &request(url, "sk-duck", &body), &body));
+    }
+
+    #[test]
+    fn sampled_and_streamed_requests_are_not_cached() {
+        let config = config("sampled");
+        let url = "https://api.openai.com/v1/chat/completions";
+        let sampled = json!({ "model": "gpt-4o", "temperature": 0.7 });
+        let streamed = json!({ "model": "gpt-4o", "temperature": 0.0, "stream": true });
+
+        put(&config, &request(url, "sk-duck", &sampled), &sampled, "quack");
+        put(&config, &request(url, "sk-duck", &streamed), &streamed, "quack");
+        assert_eq!(None, get(&config, &request(url, "sk-duck", &sampled), &sampled));
+        assert_eq!(None, get(&config, &request(url, "sk-duck", &streamed), &streamed));
+    }
+}
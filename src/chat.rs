@@ -1,37 +1,266 @@
+use std::collections::HashMap;
+use std::fs::{self,File};
+use std::io::{self,Write};
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::time::{Duration,Instant};
 use async_recursion::async_recursion;
-use clap::{Args};
+use clap::{Args,ValueEnum};
 use serde::{Serialize,Deserialize};
 use reqwest::Client;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{self,BoxStream,StreamExt};
+use reqwest_eventsource::{EventSource,Event};
 use derive_more::From;
-use tiktoken_rs::p50k_base;
-use crate::openai::chat::OpenAIChatCommand;
+use tiktoken_rs::cl100k_base_singleton;
+use tokio_util::sync::CancellationToken;
+use crate::openai::chat::{OpenAIChatCommand,OpenAIChatModel};
 use crate::openai::OpenAIError;
+use crate::anthropic::{AnthropicChatCommand,AnthropicError};
+use crate::anthropic::chat::AnthropicChatModel;
 use crate::completion::{CompletionOptions,CompletionFile,ClashingArgumentsError};
-use crate::Config;
+use crate::session::{Model,ModelFocus};
+use crate::config::{ApiKeyError,ApiKeyFileError,Config,InvalidApiKeyError};
 
 const CHAT_TOKENS_MAX: usize = 4096;
 
+/// How often the response streamed so far is saved to the session file while it streams in
+const CHAT_STREAM_PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Args, Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ChatCommand {
     #[command(flatten)]
     #[serde(flatten)]
     pub completion: CompletionOptions,
 
+    /// The provider the chat is sent to. Defaults to the provider in the config file, or openai
+    #[arg(value_enum, long)]
+    pub provider: Option<ChatProvider>,
+
+    /// The chat model to use. Defaults to gpt-4
+    #[arg(value_enum, long, short)]
+    pub model: Option<OpenAIChatModel>,
+
+    /// The chat model to use with the anthropic provider. Defaults to claude-3-5-sonnet
+    #[arg(value_enum, long)]
+    pub anthropic_model: Option<AnthropicChatModel>,
+
+    /// Model size, picks the provider's chat model by size like the complete command does when
+    /// no model is given
+    #[arg(value_enum, long)]
+    pub model_size: Option<Model>,
+
+    /// Model focus, used with model_size
+    #[arg(value_enum, long)]
+    pub model_focus: Option<ModelFocus>,
+
+    /// The system message. It isn't sent when the transcript starts with a SYSTEM: message of its
+    /// own, which is sent instead
     #[arg(long, short)]
     pub system: Option<String>,
 
+    /// Read the system message from a file, for system messages too long to pass inline
+    #[arg(long)]
+    pub system_file: Option<PathBuf>,
+
     #[arg(long, short)]
     pub direction: Option<String>,
+
+    /// The maximum number of tokens to generate in the response. OpenAI's reasoning models are
+    /// sent this as max_completion_tokens, since they reject max_tokens. Defaults to the
+    /// provider's limit for OpenAI and to what tokens_balance leaves of tokens_max for Anthropic
+    #[arg(long)]
+    pub max_tokens: Option<usize>,
+
+    /// Penalizes tokens that have already appeared in the conversation, making the AI more likely
+    /// to talk about new topics. Ranges from -2 to 2
+    #[arg(long)]
+    pub presence_penalty: Option<f32>,
+
+    /// Penalizes tokens based on how often they've appeared in the conversation, making the AI
+    /// less likely to repeat itself verbatim. Ranges from -2 to 2
+    #[arg(long)]
+    pub frequency_penalty: Option<f32>,
+
+    /// Print and save the AI's responses verbatim, without adding or stripping the AI prefix.
+    /// Note that unlabeled responses can't be told apart from your own messages when the
+    /// transcript is read back, so this is best combined with once or no_context.
+    #[arg(long)]
+    pub raw: Option<bool>,
+
+    /// The format responses are printed in. The json format prints one object per response with
+    /// the response id, model, role, content, finish reason, token usage and system fingerprint,
+    /// and disables streaming.
+    #[arg(value_enum, long)]
+    pub format: Option<OutputFormat>,
+
+    /// The format the session is saved in. The json format saves the messages with their roles,
+    /// the model, temperature and token count next to the session file, and resumes the
+    /// conversation from it. Defaults to text
+    #[arg(value_enum, long)]
+    pub transcript_format: Option<TranscriptFormat>,
+
+    /// Attach an image to your latest message, either a link or a base64 encoded data URL. Can be
+    /// given multiple times
+    #[arg(long)]
+    #[serde(skip)]
+    pub image: Vec<String>,
+
+    /// Automatically ask the AI to continue when a response is cut off by the token limit, and
+    /// concatenate the continuation to the response. Disables streaming
+    #[arg(long = "continue")]
+    pub continue_truncated: Option<bool>,
+
+    /// Force the AI to respond with a valid JSON object. OpenAI requires the word JSON to be
+    /// mentioned in the system message or the conversation when this is enabled
+    #[arg(long)]
+    pub json_mode: Option<bool>,
+
+    /// A JSON schema the AI's response has to follow. The response is checked against the schema
+    /// before it's returned
+    #[arg(skip)]
+    pub json_schema: Option<serde_json::Value>,
+
+    /// Request the log probability of each token in the response. They're included in the json
+    /// output format
+    #[arg(long)]
+    pub logprobs: Option<bool>,
+
+    /// The number of most likely alternatives returned with each token's log probability.
+    /// Ranges from 0 to 20, requires logprobs
+    #[arg(long)]
+    pub top_logprobs: Option<u8>,
+
+    /// Tools the AI may call instead of responding with a message
+    #[arg(skip)]
+    pub tools: Option<Vec<ChatTool>>,
+
+    /// Example exchanges sent after the system message and before the transcript, for few shot
+    /// prompting. They're part of the instructions, so they're never trimmed from the context
+    #[arg(skip)]
+    pub examples: Option<Vec<ChatMessage>>,
+
+    /// Messages sent as they are instead of the ones built from the system message, the examples
+    /// and the transcript. Start from build_messages to trim or redact the messages before they're
+    /// sent, this is best combined with append or return_response since every turn sends them
+    #[arg(skip)]
+    #[serde(skip)]
+    pub messages: Option<Vec<ChatMessage>>,
+
+    /// Receives the AI's response as it's printed, as it streams in or all at once when it isn't
+    /// streamed. Defaults to printing to stdout
+    #[arg(skip)]
+    #[serde(skip)]
+    pub stream_handler: Option<ChatStreamHandler>,
+
+    /// Return the AI's response to the caller as soon as it's generated, which ends the chat after
+    /// a single response
+    #[arg(skip)]
+    #[serde(skip)]
+    pub return_response: bool,
+
+    /// Stops a streamed response early when cancelled. The response streamed so far is saved to
+    /// the transcript and returned
+    #[arg(skip)]
+    #[serde(skip)]
+    pub cancellation: Option<CancellationToken>,
 }
 
 impl ChatCommand {
+    /// Builds the messages this command would send to the AI, including the appended message,
+    /// without sending them. Nothing is written to the transcript.
+    pub fn build_messages(&self, config: &Config) -> Result<Vec<ChatMessage>, ChatError> {
+        build_messages(&self.options_with_append(config)?)
+    }
+
+    /// Streams the AI's response as content deltas instead of printing it, for forwarding the
+    /// response somewhere else. The message sent is the append option since nothing is read from
+    /// stdin, and the response isn't saved to the transcript.
+    pub async fn stream(&self, client: &Client, config: &Config) -> Result<ChatStream, ChatError> {
+        let options = self.options_with_append(config)?;
+        let backend: Box<dyn ChatBackend> = match options.provider {
+            ChatProvider::OpenAI => Box::new(OpenAIChatCommand::try_from(options)?),
+            ChatProvider::Anthropic => Box::new(AnthropicChatCommand::try_from(options)?)
+        };
+
+        backend.stream(client, config).await
+    }
+
+    /// The resolved options with the appended message added to the transcript in memory, without
+    /// writing it to the session file
+    fn options_with_append(&self, config: &Config) -> Result<ChatOptions, ChatError> {
+        let mut options = ChatOptions::try_from((self, config))?;
+
+        if let (false, Some(append)) = (options.ai_responds_first, &options.completion.append) {
+            let line = format!("{}: {}", options.prefix_user, append.trim());
+            options.file.transcript += &line;
+            options.file.transcript += "\n";
+            options.file.last_read_input = line;
+        }
+
+        Ok(options)
+    }
+
+    /// Checks the API key and the connection to the provider before starting a chat, without
+    /// generating anything. A rejected key is returned as ApiKeyRejected, a missing one as
+    /// Unauthorized and network failures as Request or Timeout errors
+    pub async fn verify(&self, client: &Client, config: &Config) -> Result<(), ChatError> {
+        let options = ChatOptions::try_from((self, config))?;
+        let api_key = options.completion.api_key.as_deref();
+
+        match options.provider {
+            ChatProvider::OpenAI => crate::openai::verify(client, config, api_key).await,
+            ChatProvider::Anthropic => crate::anthropic::verify(client, config, api_key).await
+        }
+    }
+
+    /// Discards everything after the first exchanges of the session, an exchange being one of your
+    /// messages and the responses to it, so the next run continues the conversation from there.
+    /// Messages before your first one, like a SYSTEM: line, are kept
+    pub fn truncate_session(&self, config: &Config, exchanges: usize) -> Result<(), ChatError> {
+        let mut options = ChatOptions::try_from((self, config))?;
+        let mut session = ChatSession::try_from(&options)?;
+        session.truncate(exchanges);
+
+        options.file.transcript = session.transcript(&options.prefix_ai, &options.prefix_user);
+        options.file.persist()?;
+
+        if let Some(path) = options.json_session_path() {
+            session.save_json(path)?;
+        }
+
+        Ok(())
+    }
+
     #[async_recursion]
     pub async fn run(&self, client: &Client, config: &Config) -> ChatResult {
+        self.run_with_options(ChatOptions::try_from((self, config))?, client, config).await
+    }
+
+    /// Runs the chat with a different system message for this invocation only. The system message
+    /// saved with the session isn't changed
+    pub async fn run_with_system(
+        &self,
+        system: &str,
+        client: &Client,
+        config: &Config) -> ChatResult
+    {
         let mut options = ChatOptions::try_from((self, config))?;
+        options.system = system.to_string();
+        self.run_with_options(options, client, config).await
+    }
+
+    async fn run_with_options(
+        &self,
+        mut options: ChatOptions,
+        client: &Client,
+        config: &Config) -> ChatResult
+    {
         let print_output = !options.completion.quiet.unwrap_or(false);
 
-        if print_output && options.file.transcript.len() > 0 {
-            print!("{}", options.file.transcript);
+        if print_output && options.format == OutputFormat::Text && options.file.transcript.len() > 0 {
+            write!(io::stdout(), "{}", options.file.transcript)?;
         }
 
         if !options.ai_responds_first {
@@ -43,75 +272,684 @@ impl ChatCommand {
             }
         }
 
-        let mut command = OpenAIChatCommand::try_from(options)?;
-        command.run(client, config).await
+        let json_path = options.json_session_path();
+        let mut backend: Box<dyn ChatBackend> = match options.provider {
+            ChatProvider::OpenAI => Box::new(OpenAIChatCommand::try_from(options)?),
+            ChatProvider::Anthropic => Box::new(AnthropicChatCommand::try_from(options)?)
+        };
+        let result = backend.run(client, config).await;
+
+        if let Some(path) = json_path {
+            backend.session()?.save_json(path)?;
+        }
+
+        result
     }
 }
 
 #[derive(Default, Debug)]
 pub(crate) struct ChatOptions {
     pub ai_responds_first: bool,
+    pub anthropic_model: AnthropicChatModel,
+    pub cancellation: CancellationToken,
     pub completion: CompletionOptions,
+    pub continue_truncated: bool,
     pub direction: Option<ChatMessage>,
+    /// Set once the dropped messages warning is printed, so a run prints it at most once
+    pub dropped_messages_warned: AtomicBool,
+    pub examples: Vec<ChatMessage>,
+    pub messages: Option<ChatMessages>,
     pub system: String,
     pub file: CompletionFile<ChatCommand>,
+    pub format: OutputFormat,
+    pub frequency_penalty: Option<f32>,
+    pub images: Vec<ChatImageUrl>,
+    pub json_mode: bool,
+    pub json_schema: Option<serde_json::Value>,
+    pub logit_bias: HashMap<u32, f32>,
+    pub logprobs: bool,
+    pub max_tokens: Option<usize>,
+    pub model: OpenAIChatModel,
     pub no_context: bool,
     pub prefix_ai: String,
     pub prefix_user: String,
+    pub presence_penalty: Option<f32>,
+    pub provider: ChatProvider,
+    pub raw: bool,
+    pub return_response: bool,
     pub stream: bool,
+    pub stream_handler: ChatStreamHandler,
     pub temperature: f32,
     pub tokens_max: usize,
-    pub tokens_balance: f32
+    pub tokens_balance: f32,
+    pub tools: Vec<ChatTool>,
+    pub transcript_format: TranscriptFormat,
+    pub top_logprobs: Option<u8>,
+    pub user: Option<String>
 }
 
 impl TryFrom<(&ChatCommand, &Config)> for ChatOptions {
     type Error = ChatError;
 
     fn try_from((command, config): (&ChatCommand, &Config)) -> Result<Self, Self::Error> {
-        let file = command.completion.load_session_file::<ChatCommand>(config, command.clone());
-        let completion = if file.file.is_some() {
+        let mut file = command.completion.load_session_file::<ChatCommand>(config, command.clone());
+        let completion = if file.path.is_some() {
             command.completion.merge(&file.overrides.completion)
         } else {
             command.completion.clone()
         };
 
-        let stream = completion.parse_stream_option()?;
-        let system = command.system
-            .clone()
-            .or_else(|| file.overrides.system.clone())
-            .clone()
-            .unwrap_or_else(|| String::from("A friendly and helpful AI assistant."));
+        let format = command.format.or(file.overrides.format).unwrap_or_default();
+        let transcript_format = command.transcript_format
+            .or(file.overrides.transcript_format)
+            .unwrap_or_default();
+        let prefix_ai = completion.prefix_ai.clone().unwrap_or_else(|| String::from("AI"));
+        let prefix_user = completion.prefix_user.clone().unwrap_or_else(|| String::from("USER"));
+
+        if let (TranscriptFormat::Json, Some(path)) = (transcript_format, &file.path) {
+            let path = json_session_path(path);
+            if path.exists() {
+                file.transcript = ChatSession::load_json(path)?.transcript(&prefix_ai, &prefix_user);
+            }
+        }
+
+        let provider = command.provider
+            .or(file.overrides.provider)
+            .or(config.provider)
+            .unwrap_or_default();
+        let continue_truncated = command.continue_truncated
+            .or(file.overrides.continue_truncated)
+            .unwrap_or(false);
+        let stream = completion.parse_stream_option()? && format == OutputFormat::Text
+            && !continue_truncated;
+        if command.system.is_some() && command.system_file.is_some() {
+            return Err(ChatError::ClashingArguments(ClashingArgumentsError::new(
+                "The system and system_file options can't be used together")));
+        }
+
+        let system_file = match (&command.system, &file.overrides.system) {
+            (Some(_), _) => None,
+            (None, Some(_)) => command.system_file.as_ref(),
+            (None, None) => command.system_file.as_ref().or(file.overrides.system_file.as_ref())
+        };
+        let system = match system_file {
+            Some(path) => fs::read_to_string(path)
+                .map(|system| system.trim_end().to_string())
+                .map_err(|error| ChatError::SystemFile { path: path.clone(), error })?,
+            None => command.system
+                .clone()
+                .or_else(|| file.overrides.system.clone())
+                .unwrap_or_else(|| String::from("A friendly and helpful AI assistant."))
+        };
+
+        let max_tokens = command.max_tokens.or(file.overrides.max_tokens);
+        if let Some(0) = max_tokens {
+            return Err(ChatError::ClashingArguments(ClashingArgumentsError::new(
+                "The max tokens should be more than 0")));
+        }
+
+        let model_size = command.model_size.or(file.overrides.model_size);
+        let model_focus = command.model_focus.or(file.overrides.model_focus);
+        let models = match (model_size, model_focus) {
+            (None, None) => None,
+            (size, focus) => Some((focus.unwrap_or_default(), size.unwrap_or(Model::XXLarge)))
+        };
+        let model = match (command.model.or(file.overrides.model), models) {
+            (Some(model), _) => model,
+            (None, Some(models)) if provider == ChatProvider::OpenAI => models.try_into()?,
+            (None, _) => OpenAIChatModel::default()
+        };
+        let anthropic_model = command.anthropic_model.or(file.overrides.anthropic_model);
+        let anthropic_model = match (anthropic_model, models) {
+            (Some(model), _) => model,
+            (None, Some(models)) if provider == ChatProvider::Anthropic => models.try_into()?,
+            (None, _) => AnthropicChatModel::default()
+        };
 
         Ok(ChatOptions {
             ai_responds_first: completion.ai_responds_first.unwrap_or(false),
+            anthropic_model,
+            cancellation: command.cancellation.clone().unwrap_or_default(),
             direction: command.direction.clone()
                 .map(|direction| ChatMessage::new(ChatRole::System, direction)),
+            dropped_messages_warned: AtomicBool::new(false),
+            examples: command.examples
+                .clone()
+                .or_else(|| file.overrides.examples.clone())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|message| ChatMessage {
+                    tokens: count_tokens(&format!("{}{}", message.role, message.content.text())),
+                    ..message
+                })
+                .collect(),
+            messages: command.messages.clone(),
+            images: command.image.iter().map(ChatImageUrl::new).collect(),
+            json_mode: command.json_mode.or(file.overrides.json_mode).unwrap_or(false),
+            json_schema: command.json_schema.clone().or_else(|| file.overrides.json_schema.clone()),
+            logit_bias: completion.logit_bias.iter().flatten().copied().collect(),
+            logprobs: command.logprobs.or(file.overrides.logprobs).unwrap_or(false),
+            max_tokens,
+            model,
+            presence_penalty: command.presence_penalty.or(file.overrides.presence_penalty),
+            provider,
+            frequency_penalty: command.frequency_penalty.or(file.overrides.frequency_penalty),
             temperature: completion.temperature.unwrap_or(0.8),
             no_context: completion.no_context.unwrap_or(false),
-            prefix_ai: completion.prefix_ai.clone().unwrap_or_else(|| String::from("AI")),
-            prefix_user: completion.prefix_user.clone().unwrap_or_else(|| String::from("USER")),
+            prefix_ai,
+            prefix_user,
+            raw: command.raw.or(file.overrides.raw).unwrap_or(false),
+            return_response: command.return_response,
             system,
             tokens_balance: completion.tokens_balance.unwrap_or(0.5),
             tokens_max: CHAT_TOKENS_MAX,
+            tools: command.tools
+                .clone()
+                .or_else(|| file.overrides.tools.clone())
+                .unwrap_or_default(),
+            top_logprobs: command.top_logprobs.or(file.overrides.top_logprobs),
+            user: completion.user.clone().or_else(|| config.user.clone()),
+            stream_handler: match (&command.stream_handler, completion.quiet) {
+                (Some(handler), _) => handler.clone(),
+                (None, Some(true)) => ChatStreamHandler::new(|_| Ok(())),
+                (None, _) => ChatStreamHandler::default()
+            },
+            transcript_format,
             completion,
+            continue_truncated,
+            format,
             stream,
             file,
         })
     }
 }
 
+/// The function behind a ChatStreamHandler
+type ChatStreamHandlerFn = dyn Fn(&str) -> io::Result<()> + Send + Sync;
+
+/// A callback that's handed the text the chat prints, each chunk of a streamed response or the
+/// whole response otherwise, so embedders can render the response into their own interface or
+/// capture it instead of printing it to stdout.
+#[derive(Clone)]
+pub struct ChatStreamHandler(Arc<ChatStreamHandlerFn>);
+
+impl ChatStreamHandler {
+    pub fn new(handler: impl Fn(&str) -> io::Result<()> + Send + Sync + 'static) -> Self {
+        ChatStreamHandler(Arc::new(handler))
+    }
+
+    /// Writes the printed text to a writer instead of stdout, flushing after each chunk
+    pub fn writer(writer: impl Write + Send + 'static) -> Self {
+        let writer = Mutex::new(writer);
+        ChatStreamHandler::new(move |text| {
+            let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+            writer.write_all(text.as_bytes())?;
+            writer.flush()
+        })
+    }
+
+    pub(crate) fn handle(&self, text: &str) -> io::Result<()> {
+        (self.0)(text)
+    }
+}
+
+impl Default for ChatStreamHandler {
+    fn default() -> Self {
+        ChatStreamHandler::new(|text| {
+            let mut stdout = io::stdout();
+            write!(stdout, "{}", text)?;
+            stdout.flush()
+        })
+    }
+}
+
+impl std::fmt::Debug for ChatStreamHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str("ChatStreamHandler")
+    }
+}
+
+/// A provider that can carry on the chat, picked by the provider option
+pub trait ChatBackend: Send {
+    fn run<'a>(&'a mut self, client: &'a Client, config: &'a Config) -> BoxFuture<'a, ChatResult>;
+
+    /// Streams the response to the conversation as content deltas, without printing it or saving
+    /// it to the transcript
+    fn stream<'a>(&'a self, client: &'a Client, config: &'a Config)
+        -> BoxFuture<'a, Result<ChatStream, ChatError>>;
+
+    /// A snapshot of the conversation so far
+    fn session(&self) -> Result<ChatSession, ChatError>;
+}
+
+/// The content deltas of a streamed response, ending after the last delta or the first error
+pub type ChatStream = BoxStream<'static, Result<String, ChatError>>;
+
+/// Parses a streamed chunk, returning None for a chunk that isn't JSON at all. Those are skipped
+/// with a warning rather than losing the rest of the response, while JSON that doesn't fit what
+/// the provider should send, like an error, is left to the caller to fail on
+pub(crate) fn parse_stream_chunk(data: &str, stream_name: &str) -> Option<serde_json::Value> {
+    match serde_json::from_str(data) {
+        Ok(chunk) => Some(chunk),
+        Err(error) => {
+            log::warn!("Skipping a malformed chunk in the {} stream: {}", stream_name, error);
+            None
+        }
+    }
+}
+
+/// Turns a provider's server sent events into a stream of content deltas. parse reads the content
+/// out of an event, returning None for the event that ends the response. The AI's label is
+/// stripped from the start of the response when prefix_ai is given
+pub(crate) fn content_stream(
+    events: EventSource,
+    prefix_ai: Option<String>,
+    parse: fn(&str) -> Result<Option<String>, ChatError>) -> ChatStream
+{
+    stream::unfold(Some((events, false)), move |state| {
+        let prefix_ai = prefix_ai.clone();
+        async move {
+            let (mut events, has_content) = state?;
+            loop {
+                let content = match events.next().await? {
+                    Ok(Event::Open) => continue,
+                    Ok(Event::Message(message)) => parse(&message.data),
+                    Err(reqwest_eventsource::Error::StreamEnded) => Ok(None),
+                    Err(reqwest_eventsource::Error::Transport(error)) if error.is_timeout() => {
+                        Err(ChatError::Timeout)
+                    },
+                    Err(error) => Err(ChatError::EventSource(Box::new(error)))
+                };
+
+                let content = match content {
+                    Ok(Some(content)) => content,
+                    Ok(None) => {
+                        events.close();
+                        return None;
+                    },
+                    Err(error) => {
+                        events.close();
+                        return Some((Err(error), None));
+                    }
+                };
+
+                let content = match &prefix_ai {
+                    Some(prefix_ai) if !has_content => {
+                        let content = content.trim_start();
+                        strip_prefix_ai(content, prefix_ai).unwrap_or(content).to_string()
+                    },
+                    _ => content
+                };
+                if content.is_empty() {
+                    continue;
+                }
+
+                return Some((Ok(content), Some((events, true))));
+            }
+        }
+    }).boxed()
+}
+
+impl ChatOptions {
+    /// The warning printed when old messages are dropped to fit the token budget, None once it's
+    /// been returned before. Every request of a chat drops them again, and repeating the warning
+    /// for each one would bury the responses
+    pub(crate) fn dropped_messages_warning(&self, dropped: usize, dropped_tokens: usize)
+        -> Option<String>
+    {
+        if self.dropped_messages_warned.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+
+        let upper_bound = (self.tokens_max as f32 * self.tokens_balance).floor() as usize;
+        Some(format!(
+            concat!(
+                "warning: Dropped the {} oldest messages ({} tokens) to fit the ",
+                "conversation in {} of the {} maximum tokens."),
+            dropped,
+            dropped_tokens,
+            upper_bound,
+            self.tokens_max))
+    }
+
+    /// Where the json transcript is saved, None unless the session is named and uses the json
+    /// transcript format
+    fn json_session_path(&self) -> Option<PathBuf> {
+        match (self.transcript_format, &self.file.path) {
+            (TranscriptFormat::Json, Some(path)) => Some(json_session_path(path)),
+            _ => None
+        }
+    }
+
+    /// Saves the response streamed so far after the transcript once CHAT_STREAM_PERSIST_INTERVAL
+    /// passed since it was last saved, so a crash mid stream doesn't lose all of it. Writing the
+    /// whole response replaces it
+    pub(crate) fn persist_partial_response(&self, response: &str, persisted_at: &mut Instant) {
+        if self.no_context || persisted_at.elapsed() < CHAT_STREAM_PERSIST_INTERVAL {
+            return;
+        }
+
+        if let Err(error) = self.file.persist_partial(response) {
+            log::warn!("Couldn't save the partial response to the session file: {}", error);
+        }
+        *persisted_at = Instant::now();
+    }
+
+    /// Trims a response and strips the AI's label the model sometimes starts it with, unless the
+    /// output is raw
+    pub(crate) fn response_content(&self, content: &str) -> String {
+        if self.raw {
+            return content.to_string();
+        }
+
+        let content = content.trim();
+        strip_prefix_ai(content, &self.prefix_ai).unwrap_or(content).to_string()
+    }
+
+    /// Writes a response to the transcript behind the AI's label, unless the output is raw.
+    /// Streamed and non streamed responses are both saved through this, so the transcript doesn't
+    /// depend on whether the response was streamed. Returns the line that was written
+    pub(crate) fn write_response(&mut self, content: &str) -> io::Result<String> {
+        let text = if self.raw {
+            content.to_string()
+        } else {
+            format!("{}{}", self.ai_label(), content)
+        };
+        self.file.write(text, self.no_context, false)
+    }
+
+    /// What a run returns once the response is in the transcript: the response itself when it's
+    /// asked for, the whole conversation when the run stops after this response, and nothing when
+    /// the next message should be read
+    pub(crate) fn response_result(&self, content: String, finish_reason: Option<String>) -> ChatResult {
+        if self.return_response {
+            return Ok(vec![ ChatMessage { finish_reason, ..ChatMessage::new(ChatRole::Ai, content) } ]);
+        }
+
+        if self.cancellation.is_cancelled() || self.completion.parse_once_option() {
+            return ChatMessages::try_from(self);
+        }
+
+        Ok(vec![])
+    }
+
+    /// Saves the session file without the partial response persist_partial_response added, for
+    /// a streamed response that ended without being written to the transcript, like tool calls or
+    /// a response that failed its checks
+    pub(crate) fn discard_partial_response(&self) {
+        if self.no_context {
+            return;
+        }
+
+        if let Err(error) = self.file.persist() {
+            log::warn!("Couldn't remove the partial response from the session file: {}", error);
+        }
+    }
+
+    /// The label the AI's responses are written to the transcript with, parse_transcript reads it
+    /// back as the AI's role
+    pub(crate) fn ai_label(&self) -> String {
+        format!("{}: ", self.prefix_ai)
+    }
+}
+
+fn json_session_path(path: &Path) -> PathBuf {
+    let mut path = path.to_path_buf().into_os_string();
+    path.push(".json");
+    PathBuf::from(path)
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum TranscriptFormat {
+    /// The labeled transcript lines after the session options
+    #[default]
+    Text,
+
+    /// The messages and chat metadata as JSON, saved next to the session file
+    Json
+}
+
+/// A structured snapshot of a chat, which can be saved and loaded to resume the conversation
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChatSession {
+    pub provider: ChatProvider,
+    pub model: String,
+    pub temperature: f32,
+    pub system: String,
+    /// The number of tokens in the system message and the conversation
+    pub tokens: usize,
+    pub messages: Vec<ChatMessage>
+}
+
+impl ChatSession {
+    /// Saves the session as JSON, going through a temporary file like the session file does
+    pub fn save_json(&self, path: impl AsRef<Path>) -> Result<(), ChatError> {
+        let path = path.as_ref();
+        let temp_path = {
+            let mut temp_path = path.to_path_buf().into_os_string();
+            temp_path.push(".tmp");
+            PathBuf::from(temp_path)
+        };
+
+        let mut file = File::create(&temp_path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        file.sync_all()?;
+        Ok(fs::rename(&temp_path, path)?)
+    }
+
+    /// Loads a session saved with save_json. Token counts aren't saved per message, so they're
+    /// counted again
+    pub fn load_json(path: impl AsRef<Path>) -> Result<Self, ChatError> {
+        let mut session: ChatSession = serde_json::from_str(&fs::read_to_string(path)?)?;
+        for message in &mut session.messages {
+            message.tokens = count_tokens(&format!("{}{}", message.role, message.content.text()));
+        }
+
+        Ok(session)
+    }
+
+    /// Keeps the messages of the first exchanges, each starting with a user message, along with
+    /// the messages before the first user message
+    pub fn truncate(&mut self, exchanges: usize) {
+        let end = self.messages
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| message.role == ChatRole::User)
+            .nth(exchanges)
+            .map_or(self.messages.len(), |(index, _)| index);
+        let removed = self.messages.split_off(end);
+        let removed_tokens: usize = removed.iter().map(|message| message.tokens).sum();
+        self.tokens = self.tokens.saturating_sub(removed_tokens);
+    }
+
+    /// Renders the messages as transcript lines labeled with the given prefixes
+    pub fn transcript(&self, prefix_ai: &str, prefix_user: &str) -> String {
+        self.messages
+            .iter()
+            .map(|message| {
+                let label = match message.role {
+                    ChatRole::Ai => prefix_ai,
+                    ChatRole::User => prefix_user,
+                    ChatRole::System => "SYSTEM",
+                    ChatRole::Tool => "TOOL"
+                };
+                format!("{}: {}\n", label, message.content.text())
+            })
+            .collect()
+    }
+}
+
+impl TryFrom<&ChatOptions> for ChatSession {
+    type Error = ChatError;
+
+    fn try_from(options: &ChatOptions) -> Result<Self, Self::Error> {
+        let system = ChatMessage::new(ChatRole::System, &options.system);
+        let messages = parse_transcript(options)?;
+
+        Ok(ChatSession {
+            provider: options.provider,
+            model: match options.provider {
+                ChatProvider::OpenAI => options.model.to_versioned(),
+                ChatProvider::Anthropic => options.anthropic_model.to_versioned()
+            }.to_string(),
+            temperature: options.temperature,
+            system: options.system.clone(),
+            tokens: system.tokens + messages.iter().map(|m| m.tokens).sum::<usize>(),
+            messages
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatProvider {
+    #[default]
+    #[value(name = "openai")]
+    OpenAI,
+    Anthropic
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum OutputFormat {
+    /// The labeled transcript line
+    #[default]
+    Text,
+
+    /// A JSON object per response, for scripting
+    Json
+}
+
 #[derive(Debug, From)]
 pub enum ChatError {
+    ApiKeyFile(ApiKeyFileError),
+    /// The provider responded with a 401, the API key is wrong or revoked
+    ApiKeyRejected,
     ClashingArguments(ClashingArgumentsError),
     ChatTranscriptionError(ChatTranscriptionError),
+    ContentFiltered,
+    /// The system message, the examples and the latest message alone take more than tokens_max
+    /// tokens
+    #[from(ignore)]
+    ContextTooLarge { tokens: usize, tokens_max: usize },
+    InvalidApiKey(InvalidApiKeyError),
     TranscriptDeserializationError(serde_json::Error),
     OpenAIError(OpenAIError),
+    /// The OpenAI account ran out of credit, this isn't retried like other rate limits
+    #[from(ignore)]
+    QuotaExceeded(OpenAIError),
+    /// The response isn't JSON or doesn't match the JSON schema it was asked to follow
+    #[from(ignore)]
+    SchemaMismatch(String),
+    /// The file given as the system_file option couldn't be read
+    #[from(ignore)]
+    SystemFile { path: PathBuf, error: io::Error },
+    AnthropicError(AnthropicError),
     NetworkError(reqwest::Error),
+    NoChoices,
+    /// The provider has no chat model for the model_size and model_focus asked for
+    NoMatchingModel,
+    #[from(ignore)]
+    Request(reqwest::Error),
+    /// The stream broke off after part of the response arrived. The partial response is saved to
+    /// the transcript before this is returned
+    #[from(ignore)]
+    StreamInterrupted { response: String, error: Box<ChatError> },
     IOError(std::io::Error),
-    EventSource(reqwest_eventsource::Error),
+    EventSource(Box<reqwest_eventsource::Error>),
+    /// The request can't be streamed, since its body can't be cloned to reconnect with
+    StreamRequest(reqwest_eventsource::CannotCloneRequestError),
+    PenaltyOutOfValidRange,
+    TemperatureOutOfValidRange,
+    TooManyStopSequences,
+    TopLogprobsOutOfValidRange,
+    #[from(ignore)]
+    UnknownModel(String),
+    Timeout,
     Unauthorized
 }
 
+impl std::fmt::Display for ChatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ChatError::OpenAIError(error) => write!(f, "{}", error),
+            ChatError::QuotaExceeded(error) => write!(f,
+                "The OpenAI account is out of credit, check your plan and billing details. {}",
+                error),
+            ChatError::AnthropicError(error) => write!(f, "{}", error),
+            ChatError::InvalidApiKey(error) => write!(f, "{}", error),
+            ChatError::SystemFile { path, error } => write!(f,
+                "Couldn't read the system message from {}: {}", path.display(), error),
+            ChatError::UnknownModel(model) => write!(f, "{} isn't a known chat model", model),
+            ChatError::NoMatchingModel => write!(f,
+                "There's no chat model for that model size and focus, pick a model instead"),
+            ChatError::StreamInterrupted { error, .. } => write!(f,
+                "The response stream was interrupted, the partial response was saved to the \
+                transcript: {}", error),
+            ChatError::SchemaMismatch(error) => write!(f,
+                "The response doesn't match the JSON schema: {}", error),
+            ChatError::ApiKeyRejected => write!(f,
+                "The provider rejected the API key, check that it's correct and still active"),
+            ChatError::Unauthorized => write!(f, "No API key was found for the provider"),
+            ChatError::ContextTooLarge { tokens, tokens_max } => write!(f,
+                "The system message, the examples and your latest message take {} tokens, which is more than \
+                the {} tokens the chat allows", tokens, tokens_max),
+            _ => write!(f, "{:#?}", self)
+        }
+    }
+}
+
+impl From<ApiKeyError> for ChatError {
+    fn from(error: ApiKeyError) -> Self {
+        match error {
+            ApiKeyError::File(error) => ChatError::ApiKeyFile(error),
+            ApiKeyError::Invalid(error) => ChatError::InvalidApiKey(error)
+        }
+    }
+}
+
+impl ChatError {
+    pub(crate) fn from_request_error(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            ChatError::Timeout
+        } else {
+            ChatError::Request(error)
+        }
+    }
+
+    pub(crate) fn from_openai_error(error: OpenAIError) -> Self {
+        if error.is_quota_exceeded() {
+            ChatError::QuotaExceeded(error)
+        } else {
+            ChatError::OpenAIError(error)
+        }
+    }
+
+    /// Wraps an error that ended a stream midway, saving the response streamed so far to the
+    /// transcript. Errors before any content arrived are returned as is
+    pub(crate) fn stream_interrupted(
+        options: &mut ChatOptions,
+        response: String,
+        error: ChatError) -> Self
+    {
+        let content = response
+            .strip_prefix(&options.ai_label())
+            .unwrap_or(&response)
+            .trim()
+            .to_string();
+        if content.is_empty() {
+            return error;
+        }
+
+        let _ = options.stream_handler.handle("\n");
+        let line = response.trim_end().to_string() + "\n";
+        if let Err(error) = options.file.write(line, options.no_context, false) {
+            eprintln!("Failed to write to the session file: {}", error);
+        }
+
+        ChatError::StreamInterrupted { response: content, error: Box::new(error) }
+    }
+}
+
 #[derive(Debug)]
 pub struct ChatTranscriptionError(pub String);
 
@@ -120,85 +958,288 @@ pub type ChatResult = Result<Vec<ChatMessage>, ChatError>;
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ChatMessage {
     pub role: ChatRole,
-    pub content: String,
+    #[serde(default, deserialize_with = "deserialize_content")]
+    pub content: ChatContent,
+    /// Tells apart participants that share a role, like several users in a conversation. OpenAI
+    /// only accepts letters, digits, underscores and dashes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ChatToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    /// Why the AI stopped generating a response it returned, as the provider reported it, like
+    /// stop or length from OpenAI and end_turn or max_tokens from Anthropic. This is None for any
+    /// other message
+    #[serde(skip)]
+    pub finish_reason: Option<String>,
     #[serde(skip)]
     pub tokens: usize
 }
 
+/// Messages that only contain tool calls are sent back with a null or missing content, which
+/// is read as empty text
+fn deserialize_content<'de, D>(deserializer: D) -> Result<ChatContent, D::Error>
+where
+    D: serde::Deserializer<'de>
+{
+    Ok(Option::<ChatContent>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// The content of a chat message, either plain text or a list of text and image parts for models
+/// that accept images
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum ChatContent {
+    Text(String),
+    Parts(Vec<ChatContentPart>)
+}
+
+impl Default for ChatContent {
+    fn default() -> Self {
+        ChatContent::Text(String::new())
+    }
+}
+
+impl ChatContent {
+    /// The text of the content, with the text parts joined by new lines
+    pub fn text(&self) -> String {
+        match self {
+            ChatContent::Text(text) => text.clone(),
+            ChatContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ChatContentPart::Text { text } => Some(&**text),
+                    ChatContentPart::ImageUrl { .. } => None
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+
+    /// Adds images after the text of the content
+    pub fn with_images(self, images: &[ChatImageUrl]) -> Self {
+        let mut parts = match self {
+            ChatContent::Text(text) => vec![ChatContentPart::Text { text }],
+            ChatContent::Parts(parts) => parts
+        };
+        parts.extend(images.iter().cloned().map(|image_url| ChatContentPart::ImageUrl { image_url }));
+
+        ChatContent::Parts(parts)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChatContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ChatImageUrl }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChatImageUrl {
+    /// A link to the image, or the image itself as a base64 encoded data URL
+    pub url: String,
+    /// The resolution the model looks at the image in: low, high or auto
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>
+}
+
+impl ChatImageUrl {
+    pub fn new(url: impl Into<String>) -> Self {
+        ChatImageUrl { url: url.into(), detail: None }
+    }
+}
+
 impl ChatMessage {
     pub fn new(role: ChatRole, content: impl AsRef<str>) -> Self {
-        let tokens = p50k_base().unwrap()
-            .encode_with_special_tokens(&format!("{}{}", role, content.as_ref()))
-            .len();
+        let tokens = count_tokens(&format!("{}{}", role, content.as_ref()));
 
         ChatMessage {
             role,
-            content: content.as_ref().to_string(),
+            content: ChatContent::Text(content.as_ref().to_string()),
+            name: None,
+            tool_calls: None,
+            tool_call_id: None,
+            finish_reason: None,
             tokens
         }
     }
+
+    /// A message with images attached after its text. Only the text counts towards the message's
+    /// tokens
+    pub fn with_images(role: ChatRole, content: impl AsRef<str>, images: &[ChatImageUrl]) -> Self {
+        let message = ChatMessage::new(role, content);
+        ChatMessage {
+            content: message.content.clone().with_images(images),
+            ..message
+        }
+    }
+
+    /// The result of running a tool, sent back to the AI in response to one of its tool calls
+    pub fn tool_result(tool_call_id: impl AsRef<str>, content: impl AsRef<str>) -> Self {
+        ChatMessage {
+            tool_call_id: Some(tool_call_id.as_ref().to_string()),
+            ..ChatMessage::new(ChatRole::Tool, content)
+        }
+    }
 }
 
-pub type ChatMessages = Vec<ChatMessage>;
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChatTool {
+    pub r#type: ChatToolType,
+    pub function: ChatFunction
+}
 
-impl TryFrom<&ChatOptions> for ChatMessages {
-    type Error = ChatError;
+impl ChatTool {
+    pub fn function(function: ChatFunction) -> Self {
+        ChatTool { r#type: ChatToolType::Function, function }
+    }
+}
 
-    fn try_from(options: &ChatOptions) -> Result<Self, Self::Error> {
-        let ChatOptions { file, system, .. } = options;
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatToolType {
+    #[default]
+    Function
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChatFunction {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// A JSON schema describing the arguments of the function
+    pub parameters: serde_json::Value
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChatToolCall {
+    pub id: String,
+    pub r#type: ChatToolType,
+    pub function: ChatFunctionCall
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ChatFunctionCall {
+    pub name: String,
+    /// The arguments the AI generated, as a JSON encoded string. These aren't guaranteed to be
+    /// valid JSON or to match the function parameters schema.
+    pub arguments: String
+}
+
+/// Strips a leading "{prefix_ai}:" label the AI sometimes echoes back. The label is matched case
+/// insensitively, the casing of the remaining text is left untouched. Returns None when the text
+/// doesn't start with the label.
+pub(crate) fn strip_prefix_ai<'a>(text: &'a str, prefix_ai: &str) -> Option<&'a str> {
+    let text = text.trim_start();
+    let label = text.get(..prefix_ai.len())?;
+    let rest = text[prefix_ai.len()..].strip_prefix(':')?;
+
+    if label.to_lowercase() == prefix_ai.to_lowercase() {
+        Some(rest.trim_start())
+    } else {
+        None
+    }
+}
+
+/// Counts the tokens in a piece of text with the cl100k_base encoding the chat models use. This is
+/// what the transcript is trimmed by before it's sent, so it matches what the API will count.
+pub fn count_tokens(text: &str) -> usize {
+    cl100k_base_singleton()
+        .lock()
+        .encode_with_special_tokens(text)
+        .len()
+}
+
+pub type ChatMessages = Vec<ChatMessage>;
 
-        let mut messages = vec![];
-        let mut message: Option<ChatMessage> = None;
+/// Whether the text before a colon in the transcript is the label of a chat role
+fn is_chat_label(label: &str, options: &ChatOptions) -> bool {
+    let label = label.trim().to_lowercase();
+    label == options.prefix_ai.to_lowercase()
+        || label == options.prefix_user.to_lowercase()
+        || matches!(&*label, "ai" | "assistant" | "user" | "system" | "tool")
+}
+
+/// Parses the transcript into messages, without the system message and without trimming it down
+/// to the token budget
+pub(crate) fn parse_transcript(options: &ChatOptions) -> Result<ChatMessages, ChatError> {
+    let mut messages = vec![];
+    let mut message: Option<ChatMessage> = None;
 
-        messages.push(ChatMessage::new(ChatRole::System, system));
+    let handle_continuing_line = |line, message: &mut Option<ChatMessage>| match message {
+        Some(m) => {
+            *message = Some(ChatMessage::new(m.role, {
+                let mut content = m.content.text();
+                content += "\n";
+                content += line;
+                content
+            }));
+            Ok(())
+        },
+        None => {
+            return Err(ChatError::ChatTranscriptionError(ChatTranscriptionError(
+                "Missing opening chat role".into()
+            )));
+        }
+    };
 
-        let handle_continuing_line = |line, message: &mut Option<ChatMessage>| match message {
-            Some(m) => {
-                *message = Some(ChatMessage::new(m.role, {
-                    let mut content = m.content.clone();
-                    content += "\n";
-                    content += line;
-                    content
-                }));
-                Ok(())
+    for line in options.file.transcript.lines() {
+        match line.split_once(':') {
+            // A colon after anything but a chat label, like "For example:", is part of the message
+            Some((role, _)) if !is_chat_label(role, options) => {
+                handle_continuing_line(line, &mut message)?
             },
-            None => {
-                return Err(ChatError::ChatTranscriptionError(ChatTranscriptionError(
-                    "Missing opening chat role".into()
-                )));
-            }
-        };
+            Some((role, dialog)) => match ChatRole::try_from((role, options)) {
+                Ok(normalized_role) => {
+                    if let Some(message) = message {
+                        messages.push(message);
+                    }
 
-        for line in file.transcript.lines() {
-            match line.split_once(':') {
-                Some((role, dialog)) => match ChatRole::try_from((role, options)) {
-                    Ok(normalized_role) => {
-                        if let Some(message) = message {
-                            messages.push(message);
-                        }
-
-                        let mut dialog = dialog.trim_start().to_string();
-                        if role != "ai" && role != "assitant" && role != "user" && role != "system"
-                            && !dialog.to_lowercase().starts_with(role) {
-                            dialog = format!("{role}: {dialog}");
-                        }
-
-                        message = Some(ChatMessage::new(normalized_role, dialog));
-                    },
-                    Err(_) => handle_continuing_line(line, &mut message)?
+                    message = Some(ChatMessage::new(normalized_role, dialog.trim_start()));
                 },
-                None => handle_continuing_line(line, &mut message)?
-            }
+                Err(_) => handle_continuing_line(line, &mut message)?
+            },
+            None => handle_continuing_line(line, &mut message)?
         }
+    }
 
-        if let Some(message) = message {
-            messages.push(message);
-        }
+    if let Some(message) = message {
+        messages.push(message);
+    }
+
+    Ok(messages)
+}
+
+impl TryFrom<&ChatOptions> for ChatMessages {
+    type Error = ChatError;
+
+    fn try_from(options: &ChatOptions) -> Result<Self, Self::Error> {
+        let ChatOptions { file, system, .. } = options;
+        let mut transcript = parse_transcript(options)?;
+
+        // A transcript that starts with its own system message is sent with that one instead of
+        // the configured one, rather than with two
+        let system = match transcript.first() {
+            Some(message) if message.role == ChatRole::System => transcript.remove(0),
+            _ => ChatMessage::new(ChatRole::System, system)
+        };
+
+        let mut messages = vec![system];
+        messages.extend(options.examples.iter().cloned());
+        messages.extend(transcript);
 
         if options.no_context {
             messages.push(ChatMessage::new(ChatRole::User, file.last_read_input.clone()));
         }
 
+        if !options.images.is_empty() {
+            if let Some(message) = messages.iter_mut().rev().find(|m| m.role == ChatRole::User) {
+                message.content = message.content.clone().with_images(&options.images);
+            }
+        }
+
         if let Some(direction) = &options.direction {
             messages.push(direction.clone());
         }
@@ -212,6 +1253,15 @@ impl TryFrom<&ChatOptions> for ChatMessages {
     }
 }
 
+/// The messages sent to the AI, either the ones the caller built or the ones built from the
+/// transcript
+pub(crate) fn build_messages(options: &ChatOptions) -> Result<ChatMessages, ChatError> {
+    match &options.messages {
+        Some(messages) => Ok(messages.clone()),
+        None => ChatMessages::try_from(options)
+    }
+}
+
 pub(crate) trait ChatMessagesInternalExt {
     fn labotomize(&self, options: &ChatOptions) -> Result<Self, ChatError> where Self: Sized;
 }
@@ -224,16 +1274,26 @@ impl ChatMessagesInternalExt for ChatMessages {
         let current_token_length: usize = self.iter().map(|m| m.tokens).sum();
 
         if current_token_length > upper_bound {
-            let system = ChatMessage::new(ChatRole::System, options.system.clone());
+            // The system message, the examples and the latest message are always sent, older
+            // messages are dropped until the rest fits
+            let instructions_len = (1 + options.examples.len()).min(self.len());
+            let (instructions, transcript) = self.split_at(instructions_len);
+            let instruction_tokens: usize = instructions.iter().map(|m| m.tokens).sum();
+            let latest = transcript.last();
+            let tokens_required = instruction_tokens + latest.map_or(0, |m| m.tokens);
+            if tokens_required > tokens_max {
+                return Err(ChatError::ContextTooLarge { tokens: tokens_required, tokens_max });
+            }
+
             let mut messages = vec![];
-            let mut remaining = upper_bound.checked_sub(system.tokens)
-                .ok_or_else(|| ChatTranscriptionError(format!(
-                    "Cannot fit your system message into the chat messages list. This means \
-                    that your tokens_max value is either too small or your system message is \
-                    too long. You're upper bound on transcript tokens is {upper_bound} and \
-                    your system message has {} tokens", system.tokens)))?;
-
-            for message in self.iter().skip(1).rev() {
+            let mut remaining = upper_bound.saturating_sub(instruction_tokens);
+
+            if let Some(latest) = latest {
+                remaining = remaining.saturating_sub(latest.tokens);
+                messages.push(latest);
+            }
+
+            for message in transcript.iter().rev().skip(1) {
                 match remaining.checked_sub(message.tokens) {
                     Some(subtracted) => {
                         remaining = subtracted;
@@ -243,7 +1303,17 @@ impl ChatMessagesInternalExt for ChatMessages {
                 }
             }
 
-            messages.push(&system);
+            messages.extend(instructions.iter().rev());
+
+            let dropped = self.len() - messages.len();
+            if dropped > 0 {
+                let kept_tokens: usize = messages.iter().map(|m| m.tokens).sum();
+                let dropped_tokens = current_token_length - kept_tokens;
+                if let Some(warning) = options.dropped_messages_warning(dropped, dropped_tokens) {
+                    eprintln!("{}", warning);
+                }
+            }
+
             Ok(messages.iter().rev().map(|i| i.clone()).cloned().collect())
         } else {
             Ok(self.clone())
@@ -251,6 +1321,8 @@ impl ChatMessagesInternalExt for ChatMessages {
     }
 }
 
+/// Who wrote a chat message. Serializes to and from the role names the APIs use, like assistant
+/// for the AI, while Display prints the transcript label.
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
 pub enum ChatRole {
     #[serde(rename = "assistant")]
@@ -258,7 +1330,9 @@ pub enum ChatRole {
     #[serde(rename = "user")]
     User,
     #[serde(rename = "system")]
-    System
+    System,
+    #[serde(rename = "tool")]
+    Tool
 }
 
 impl std::fmt::Display for ChatRole {
@@ -266,7 +1340,8 @@ impl std::fmt::Display for ChatRole {
         write!(f, "{}", match self {
             Self::Ai => "AI: ",
             Self::User => "USER: ",
-            Self::System => "SYSTEM: "
+            Self::System => "SYSTEM: ",
+            Self::Tool => "TOOL: "
         })
     }
 }
@@ -290,6 +1365,7 @@ impl TryFrom<(&str, &ChatOptions)> for ChatRole {
             "ai" |
             "assistant" => Ok(ChatRole::Ai),
             "system" => Ok(ChatRole::System),
+            "tool" => Ok(ChatRole::Tool),
             _ => Ok(ChatRole::User),
         }
     }
@@ -1,7 +1,7 @@
 use serde_json::json;
 use serde::{Deserialize,Serialize};
 use crate::session::{SessionResult,SessionOptions,SessionError,Model,ModelFocus};
-use crate::{Config};
+use crate::{request,Config};
 use reqwest::Client;
 use reqwest::header::HeaderValue;
 use uuid::Uuid;
@@ -9,6 +9,9 @@ use std::env;
 
 #[derive(Debug, Default)]
 pub struct CohereSessionCommand {
+    api_key: Option<String>,
+    dry_run: bool,
+    max_tokens: usize,
     model: CohereModel,
     temperature: CohereTemperature,
     response_count: usize
@@ -24,6 +27,9 @@ impl TryFrom<&SessionOptions> for CohereSessionCommand {
         }
 
         Ok(Self {
+            api_key: options.completion.api_key.clone(),
+            dry_run: options.completion.dry_run.unwrap_or(false),
+            max_tokens: options.max_tokens.unwrap_or(100),
             temperature: CohereTemperature::try_from(options.completion.temperature.unwrap_or(0.8))?,
             model: CohereModel::try_from(options.model)?,
             response_count: options.completion.response_count.unwrap_or(1)
@@ -37,26 +43,40 @@ impl CohereSessionCommand {
         config: &Config,
         prompt: &str) -> SessionResult
     {
+        let body = json!({
+            "model": self.model.to_versioned(),
+            "prompt": &prompt,
+            "max_tokens": self.max_tokens,
+            "return_likelihoods": "NONE",
+            "truncate": "NONE",
+            "num_generations": self.response_count,
+            "temperature": self.temperature.0,
+            "stop_sequences": [ "HUMAN:", "AI:" ]
+        });
+
+        if self.dry_run {
+            println!("{}", serde_json::to_string_pretty(&body)?);
+            return Ok(vec![]);
+        }
+
         let request = client.post("https://api.cohere.ai/generate")
-            .bearer_auth(env::var("COHERE_API_KEY")
-                .ok()
+            .bearer_auth(self.api_key.clone()
+                .or_else(|| env::var("COHERE_API_KEY").ok())
                 .or_else(|| config.api_key_cohere.clone())
                 .ok_or_else(|| SessionError::Unauthorized)?
             )
             .header("Cohere-Version", HeaderValue::from_static("2022-12-06"))
-            .json(&json!({
-                "model": self.model.to_versioned(),
-                "prompt": &prompt,
-                "max_tokens": 100,
-                "return_likelihoods": "NONE",
-                "truncate": "NONE",
-                "num_generations": self.response_count,
-                "temperature": self.temperature.0,
-                "stop_sequences": [ "HUMAN:", "AI:" ]
-            }))
-            .send()
+            .json(&body);
+        let request = request::prepare(request, config);
+
+        let request = match config.timeout() {
+            Some(timeout) => request.timeout(timeout),
+            None => request
+        };
+
+        let request = request::send(request, config)
             .await
-            .expect("Failed to send completion");
+            .map_err(SessionError::from_request_error)?;
 
         if !request.status().is_success() {
             let error: CohereError = request.json()
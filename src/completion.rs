@@ -3,6 +3,7 @@ use serde::{Serialize,Deserialize};
 use serde::de::DeserializeOwned;
 use std::fs::{self,File,OpenOptions};
 use std::io::{self,Write};
+use std::path::PathBuf;
 use crate::Config;
 use derive_more::Constructor;
 
@@ -12,15 +13,33 @@ pub struct CompletionOptions {
     #[arg(long)]
     pub ai_responds_first: Option<bool>,
 
-    /// Append a string to an existing session and get only the latest response.
+    /// The API key of the provider, takes precedence over the environment and the config file.
+    /// This is never saved to the session file.
+    #[arg(long)]
+    #[serde(skip)]
+    pub api_key: Option<String>,
+
+    /// Append a string to an existing session and get only the latest response. The chat ends
+    /// after the response unless once is set to false.
     #[arg(long)]
     pub append: Option<String>,
 
     /// Temperature of the model, the allowed range of this value is different across providers,
-    /// for OpenAI it's 0 - 2, and Cohere uses a 0 - 5 scale.
+    /// for OpenAI it's 0 - 2, and Cohere uses a 0 - 5 scale. OpenAI requests at a temperature of
+    /// 0 are sent with a fixed seed unless one is given, so their results are reproducible.
     #[arg(long, short)]
     pub temperature: Option<f32>,
 
+    /// Nucleus sampling, the model only considers the tokens making up the top_p probability
+    /// mass. If both this and temperature are provided they're both sent and it's left up to the
+    /// provider to decide how to combine them.
+    #[arg(long)]
+    pub top_p: Option<f32>,
+
+    /// Print the request that would be sent to the provider instead of sending it
+    #[arg(long)]
+    pub dry_run: Option<bool>,
+
     /// Saves your conversation context using the session name
     #[arg(short, long)]
     pub name: Option<String>,
@@ -30,7 +49,8 @@ pub struct CompletionOptions {
     #[arg(long)]
     pub no_context: Option<bool>,
 
-    /// Only do one question / answer cycle and return the result.
+    /// Only do one question / answer cycle and return the result. Defaults to true when append is
+    /// used.
     #[arg(long)]
     pub once: Option<bool>,
 
@@ -38,10 +58,17 @@ pub struct CompletionOptions {
     #[arg(long)]
     pub overwrite: Option<bool>,
 
-    /// Only write output the session file
+    /// Only write output the session file, a streamed response is collected without being
+    /// printed
     #[arg(long)]
     pub quiet: Option<bool>,
 
+    /// Sample deterministically (on a best effort basis) so repeated requests with the same seed
+    /// and parameters return the same result. OpenAI requests at a temperature of 0 default to a
+    /// fixed seed
+    #[arg(long)]
+    pub seed: Option<u64>,
+
     /// Prefix ai responses with the supplied string. This can be used for labels if your prompt has
     /// a conversational style. Defaults to "AI"
     #[arg(long)]
@@ -52,14 +79,45 @@ pub struct CompletionOptions {
     #[arg(long)]
     pub prefix_user: Option<String>,
 
-    /// Number of responses to generate
-    #[arg(skip)]
+    /// Raise or lower the likelihood of a token appearing in the response, given as
+    /// token_id=bias with a bias from -100 to 100. A bias of -100 bans the token. Can be given
+    /// more than once
+    #[arg(long, value_parser = parse_logit_bias)]
+    pub logit_bias: Option<Vec<(u32, f32)>>,
+
+    /// Number of responses to generate. More than one response is returned without streaming
+    #[arg(long)]
     pub response_count: Option<usize>,
 
-    /// Stream the output to the terminal
+    /// Print the token usage reported by the provider to stderr after each completion
+    #[arg(long)]
+    pub show_usage: Option<bool>,
+
+    /// Print the estimated cost of each completion in dollars to stderr, based on the token
+    /// usage and the model's list price
+    #[arg(long)]
+    pub show_cost: Option<bool>,
+
+    /// Print the id of each response and the model that generated it to stderr. The model can
+    /// be a more specific version than the one that was asked for
+    #[arg(long)]
+    pub show_response_info: Option<bool>,
+
+    /// Stop generating the response when the model outputs this sequence. Can be given more
+    /// than once, OpenAI allows up to 4 stop sequences.
+    #[arg(long)]
+    pub stop: Option<Vec<String>>,
+
+    /// Stream the output to the terminal. Pass --stream false to wait for the whole response
+    /// instead, the response is the same either way
     #[arg(long)]
     pub stream: Option<bool>,
 
+    /// An identifier for the end user sent along with the request, which lets the provider
+    /// detect abuse. Takes precedence over the user in the config file
+    #[arg(long)]
+    pub user: Option<String>,
+
     /// The number of maximum total tokens to allow. The maximum upper value of this is dependant on
     /// the model you're currently using, but often it's 4096.
     #[arg(long)]
@@ -78,15 +136,25 @@ impl CompletionOptions {
 
         CompletionOptions {
             ai_responds_first: original.ai_responds_first.or(merged.ai_responds_first),
+            api_key: original.api_key.or(merged.api_key),
             append: original.append.or(merged.append),
+            dry_run: original.dry_run.or(merged.dry_run),
             temperature: original.temperature.or(merged.temperature),
+            top_p: original.top_p.or(merged.top_p),
             name: original.name.or(merged.name),
             overwrite: original.overwrite.or(merged.overwrite),
             once: original.once.or(merged.once),
+            logit_bias: original.logit_bias.or(merged.logit_bias),
             quiet: original.quiet.or(merged.quiet),
             prefix_ai: original.prefix_ai.or(merged.prefix_ai),
             prefix_user: original.prefix_user.or(merged.prefix_user),
+            seed: original.seed.or(merged.seed),
+            show_usage: original.show_usage.or(merged.show_usage),
+            show_cost: original.show_cost.or(merged.show_cost),
+            show_response_info: original.show_response_info.or(merged.show_response_info),
+            stop: original.stop.or(merged.stop),
             stream: original.stream.or(merged.stream),
+            user: original.user.or(merged.user),
             tokens_max: original.tokens_max.or(merged.tokens_max),
             tokens_balance: original.tokens_balance.or(merged.tokens_balance),
             no_context: original.no_context.or(merged.no_context),
@@ -125,49 +193,50 @@ impl CompletionOptions {
                 path
             };
 
-            let mut transcript = String::new();
-            let file = match fs::read_to_string(&path) {
+            let session_config = fs::read_to_string(&path)
+                .map(|session_config| normalize_line_endings(&session_config));
+
+            match session_config {
                 Ok(mut session_config) if session_config.find("<->").is_some() => {
                     let divider_index = session_config.find("<->").unwrap();
 
-                    transcript = session_config
+                    let transcript = session_config
                         .split_off(divider_index + 4)
                         .trim_start()
                         .to_string();
+                    let header = session_config.clone();
                     session_config.truncate(divider_index);
                     overrides = serde_yaml::from_str(&session_config)
                         .expect("Serializing self to yaml config should work 100% of the time");
 
-                    OpenOptions::new()
-                        .append(true)
-                        .create(true)
-                        .open(path)
-                        .expect("Unable to open session file")
+                    CompletionFile {
+                        path: Some(path),
+                        header,
+                        overrides,
+                        transcript,
+                        last_read_input: String::new(),
+                        last_written_input: String::new()
+                    }
                 },
                 _ => {
                     let config = serde_yaml::to_string(&overrides)
                         .expect("Serializing self to yaml config should work 100% of the time");
 
-                    let mut file = OpenOptions::new()
-                        .append(true)
-                        .create(true)
-                        .open(path)
-                        .expect("Unable to open session file");
+                    let file = CompletionFile {
+                        path: Some(path),
+                        header: format!("{}<->\n", &config),
+                        overrides,
+                        transcript: String::new(),
+                        last_read_input: String::new(),
+                        last_written_input: String::new()
+                    };
 
-                    if let Err(e) = writeln!(file, "{}<->", &config) {
+                    if let Err(e) = file.persist() {
                         eprintln!("Couldn't write new configuration to file: {}", e);
                     }
 
                     file
                 }
-            };
-
-            CompletionFile {
-                file: Some(file),
-                overrides,
-                transcript,
-                last_read_input: String::new(),
-                last_written_input: String::new()
             }
         });
 
@@ -176,9 +245,8 @@ impl CompletionOptions {
 
     pub fn parse_stream_option(&self) -> Result<bool, ClashingArgumentsError> {
         match (self.quiet, self.stream) {
-            (Some(true), Some(true)) => return Err(ClashingArgumentsError::new(
-                "Having both quiet and stream enabled doesn't make sense.".into()
-            )),
+            // The response is still streamed, it's just not printed
+            (Some(true), Some(true)) => Ok(true),
             (Some(true), None) |
             (Some(true), Some(false)) |
             (None, Some(false)) |
@@ -190,6 +258,12 @@ impl CompletionOptions {
         }
     }
 
+    /// Whether the chat ends after a single exchange. Appending only does one exchange unless
+    /// once is explicitly disabled, in which case the chat carries on interactively.
+    pub fn parse_once_option(&self) -> bool {
+        self.once.unwrap_or(self.append.is_some())
+    }
+
     pub fn validate(&self) -> Result<(), ClashingArgumentsError> {
         if self.name.is_none() {
             if self.append.is_some() {
@@ -208,13 +282,15 @@ impl CompletionOptions {
                 "Specifying that the ai responds first with the append option is nonsensical"));
         }
 
-        if let Some(count) = self.response_count {
-            if count == 0 {
-                return Err(ClashingArgumentsError::new("The response count should be more than 0"));
-            }
-        }
+        self.validate_response_count()
+    }
 
-        Ok(())
+    /// Rejects a response count of 0, the chat and session commands both check it this way
+    pub fn validate_response_count(&self) -> Result<(), ClashingArgumentsError> {
+        match self.response_count {
+            Some(0) => Err(ClashingArgumentsError::new("The response count should be more than 0")),
+            _ => Ok(())
+        }
     }
 }
 
@@ -225,7 +301,10 @@ pub struct ClashingArgumentsError {
 
 #[derive(Debug, Default)]
 pub struct CompletionFile<T: Clone + Default + DeserializeOwned + Serialize> {
-    pub file: Option<File>,
+    /// The session file the transcript is saved to, None when the session isn't named
+    pub path: Option<PathBuf>,
+    /// The serialized session options and divider preceding the transcript in the session file
+    pub header: String,
     pub overrides: T,
     pub transcript: String,
     pub last_read_input: String,
@@ -237,13 +316,9 @@ where
     T: Clone + Default + DeserializeOwned + Serialize
 {
     pub fn write_words(&mut self, words: String) -> io::Result<String> {
-        match &mut self.file {
-            Some(file) => match write!(file, "{}", words) {
-                Ok(()) => { self.transcript += &words; Ok(words) },
-                Err(e) => Err(e)
-            },
-            None => { self.transcript += &words; Ok(words) }
-        }
+        self.transcript += &normalize_line_endings(&words);
+        self.persist()?;
+        Ok(words)
     }
 
     pub fn write(&mut self, line: String, no_context: bool, is_read: bool) -> io::Result<String> {
@@ -255,21 +330,41 @@ where
             return Ok(line)
         }
 
-        match &mut self.file {
-            Some(file) => match writeln!(file, "{}", self.last_written_input) {
-                Ok(()) => {
-                    self.transcript += &self.last_written_input;
-                    self.transcript += "\n";
-                    Ok(line)
-                },
-                Err(e) => Err(e)
-            },
-            None => {
-                self.transcript += &self.last_written_input;
-                self.transcript += "\n";
-                Ok(line)
-            }
-        }
+        self.transcript += &normalize_line_endings(&line);
+        self.transcript += "\n";
+        self.persist()?;
+        Ok(line)
+    }
+
+    /// Saves the session file by writing it to a temporary file next to it and renaming that into
+    /// place, so an interrupted write never leaves a half written session behind.
+    pub fn persist(&self) -> io::Result<()> {
+        self.persist_with("")
+    }
+
+    /// Saves the session file with a partial response after the transcript, without adding it to
+    /// the transcript. The next write or persist replaces it.
+    pub fn persist_partial(&self, partial: &str) -> io::Result<()> {
+        self.persist_with(&normalize_line_endings(partial))
+    }
+
+    fn persist_with(&self, partial: &str) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        let temp_path = {
+            let mut temp_path = path.clone().into_os_string();
+            temp_path.push(".tmp");
+            PathBuf::from(temp_path)
+        };
+
+        let mut file = File::create(&temp_path)?;
+        file.write_all(self.header.as_bytes())?;
+        file.write_all(self.transcript.as_bytes())?;
+        file.write_all(partial.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
     }
 
     pub fn read(
@@ -286,7 +381,7 @@ where
         line
             .and_then(|line| {
                 let line = match &prefix_user {
-                    Some(prefix) if !line.to_lowercase().starts_with(prefix) => {
+                    Some(prefix) if !line.to_lowercase().starts_with(&prefix.to_lowercase()) => {
                         format!("{}: {}", prefix, line)
                     },
                     _ => line
@@ -297,11 +392,36 @@ where
             .and_then(|line| if no_context {
                 Some(line)
             } else {
-                self.write(line, no_context, true).ok()
+                match self.write(line, no_context, true) {
+                    Ok(line) => Some(line),
+                    Err(error) => {
+                        eprintln!("Failed to write to the session file: {}", error);
+                        None
+                    }
+                }
             })
     }
 }
 
+/// Transcripts always use \n line endings, so session files written on Windows or edited with a
+/// Windows editor parse the same as any other
+fn normalize_line_endings(text: &str) -> String {
+    text.replace("\r\n", "\n")
+}
+
+fn parse_logit_bias(bias: &str) -> Result<(u32, f32), String> {
+    let (token, bias) = bias.split_once('=')
+        .ok_or_else(|| String::from("expected a token_id=bias pair"))?;
+    let token = token.trim().parse::<u32>().map_err(|error| error.to_string())?;
+    let bias = bias.trim().parse::<f32>().map_err(|error| error.to_string())?;
+
+    if !(-100.0..=100.0).contains(&bias) {
+        return Err(String::from("the bias has to be between -100 and 100"));
+    }
+
+    Ok((token, bias))
+}
+
 fn read_next_user_line(prefix_user: Option<&str>) -> Option<String> {
     let mut rl = rustyline::Editor::<()>::new().expect("Failed to create rusty line editor");
     let prefix = match prefix_user {
@@ -314,3 +434,61 @@ fn read_next_user_line(prefix_user: Option<&str>) -> Option<String> {
         Err(_) => None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ChatCommand;
+
+    #[test]
+    fn partial_responses_are_replaced_by_the_whole_response() {
+        let path = std::env::temp_dir().join(format!("ai-partial-{}", std::process::id()));
+        let mut file = CompletionFile::<ChatCommand> {
+            path: Some(path.clone()),
+            header: String::from("<->\n"),
+            ..CompletionFile::default()
+        };
+        file.write("USER: hey".into(), false, true).unwrap();
+
+        file.persist_partial("AI: qua").unwrap();
+        assert_eq!("<->\nUSER: hey\nAI: qua", fs::read_to_string(&path).unwrap());
+        assert_eq!("USER: hey\n", file.transcript);
+
+        file.write("AI: quack".into(), false, false).unwrap();
+        assert_eq!("<->\nUSER: hey\nAI: quack\n", fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn partial_responses_are_dropped_by_persisting_the_transcript() {
+        let path = std::env::temp_dir().join(format!("ai-partial-dropped-{}", std::process::id()));
+        let mut file = CompletionFile::<ChatCommand> {
+            path: Some(path.clone()),
+            header: String::from("<->\n"),
+            ..CompletionFile::default()
+        };
+        file.write("USER: hey".into(), false, true).unwrap();
+
+        file.persist_partial("AI: qua").unwrap();
+        file.persist().unwrap();
+        assert_eq!("<->\nUSER: hey\n", fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn logit_biases_are_parsed_from_token_bias_pairs() {
+        assert_eq!(Ok((50256, -100.0)), parse_logit_bias("50256=-100"));
+        assert_eq!(Ok((1, 5.5)), parse_logit_bias(" 1 = 5.5 "));
+        assert_eq!(Ok((2, 100.0)), parse_logit_bias("2=100"));
+
+        for malformed in ["", "50256", "50256:-100", "=5", "50256="] {
+            assert!(parse_logit_bias(malformed).is_err(), "{}", malformed);
+        }
+        for out_of_range in ["1=-100.5", "1=101", "1=inf", "1=NaN"] {
+            assert!(parse_logit_bias(out_of_range).is_err(), "{}", out_of_range);
+        }
+        for not_a_token in ["quack=1", "-1=1", "1.5=1", "4294967296=1"] {
+            assert!(parse_logit_bias(not_a_token).is_err(), "{}", not_a_token);
+        }
+    }
+}
@@ -5,7 +5,7 @@ use serde_json::json;
 use reqwest::{Client,StatusCode};
 use reqwest::header::HeaderValue;
 use crate::voice::{VoiceGenerate,VoiceList,VoiceError,VoiceResult};
-use crate::Config;
+use crate::{request,Config};
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct ElevenLabsGenerateCommand {
@@ -94,10 +94,12 @@ impl ElevenLabsGenerateCommand {
                 .as_ref()
                 .map(|s| HeaderValue::from_str(&*s).unwrap())
                 .ok_or_else(|| VoiceError::Unauthorized)?)
-            .json(&json)
+            .json(&json);
+        let request = request::prepare(request, config)
             .send()
             .await
             .expect("Failed to send voice request");
+        request::observe(&request, config);
 
         match request.status() {
             StatusCode::UNAUTHORIZED => Err(VoiceError::Unauthorized),
@@ -151,10 +153,12 @@ impl ElevenLabsListCommand {
             .header("xi-api-key", &config.api_key_eleven_labs
                 .as_ref()
                 .map(|s| HeaderValue::from_str(&*s).unwrap())
-                .ok_or_else(|| VoiceError::Unauthorized)?)
+                .ok_or_else(|| VoiceError::Unauthorized)?);
+        let request = request::prepare(request, config)
             .send()
             .await
             .expect("Failed to send voice request");
+        request::observe(&request, config);
 
         match request.status() {
             StatusCode::UNAUTHORIZED => Err(VoiceError::Unauthorized),
@@ -6,7 +6,9 @@ use serde::{Deserialize,Serialize};
 use serde_json::json;
 use rustc_serialize::base64::FromBase64;
 use derive_more::{From,TryInto};
-use crate::openai::OpenAIError;
+use crate::config::{ApiKeyError,ApiKeyFileError,Config,InvalidApiKeyError};
+use crate::openai::{self,OpenAIEndpoint,OpenAIError};
+use crate::request;
 
 #[derive(Clone, Debug, Args)]
 pub struct ImageCommand {
@@ -47,13 +49,39 @@ pub type ImageResult = Result<Vec<ImageData>, ImageError>;
 
 #[derive(Debug, From)]
 pub enum ImageError {
+    ApiKeyFile(ApiKeyFileError),
+    InvalidApiKey(InvalidApiKeyError),
     OpenAIError(OpenAIError),
-    DeserializeError(reqwest::Error)
+    DeserializeError(reqwest::Error),
+    #[from(ignore)]
+    Request(reqwest::Error),
+    Timeout,
+    Unauthorized
+}
+
+impl From<ApiKeyError> for ImageError {
+    fn from(error: ApiKeyError) -> Self {
+        match error {
+            ApiKeyError::File(error) => ImageError::ApiKeyFile(error),
+            ApiKeyError::Invalid(error) => ImageError::InvalidApiKey(error)
+        }
+    }
+}
+
+impl ImageError {
+    fn from_request_error(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            ImageError::Timeout
+        } else {
+            ImageError::Request(error)
+        }
+    }
 }
 
 impl ImageCommand {
-    pub async fn run(&self, client: &Client) -> ImageResult {
-        let request = client.post("https://api.openai.com/v1/images/generations")
+    pub async fn run(&self, client: &Client, config: &Config) -> ImageResult {
+        let request = openai::post(client, config, None, OpenAIEndpoint::ImageGenerations)?
+            .ok_or(ImageError::Unauthorized)?
             .json(&json!({
                 "prompt": &self.prompt,
                 "n": self.count,
@@ -69,11 +97,17 @@ impl ImageCommand {
                         PictureFormat::Binary => "b64_json"
                     }
                 }
-            }))
-            .send()
+            }));
+        let request = match config.timeout() {
+            Some(timeout) => request.timeout(timeout),
+            None => request
+        };
+
+        let request = request::send(request, config)
             .await
-            .expect("Failed to send completion");
+            .map_err(ImageError::from_request_error)?;
 
+        log::debug!("OpenAI responded to the image request with {}", request.status());
         if !request.status().is_success() {
             return Err(ImageError::OpenAIError(request.json().await?));
         }
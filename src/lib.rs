@@ -4,13 +4,37 @@ mod eleven_labs;
 mod session;
 mod image;
 mod openai;
+mod pricing;
+mod anthropic;
+mod cache;
 mod cohere;
 mod config;
+mod request;
+mod schema;
 mod voice;
 
-pub use config::{Config,JSONConfig,DEFAULT_CONFIG_FILE};
+pub use config::{
+    ApiKeyError,
+    ApiKeyFileError,
+    AzureConfig,
+    Config,
+    JSONConfig,
+    DEFAULT_AZURE_API_VERSION,
+    DEFAULT_OPENAI_API_PATH_PREFIX,
+    DEFAULT_CONFIG_FILE,
+    InvalidApiKeyError,
+    DEFAULT_OPENAI_BASE_URL
+};
 pub use completion::{CompletionOptions};
+pub use request::{RequestMiddleware,RequestStats};
+pub use pricing::{estimate_cost,MODEL_PRICES};
 pub use session::{SessionCommand,SessionResult,SessionResultExt,SessionError};
+pub use openai::list_models;
+pub use openai::session::{OpenAISessionCommand,OpenAIModel,OpenAITemperature};
+pub use openai::error::{OpenAIError,OpenAIErrorInner};
+pub use openai::chat::{OpenAIChatLogprobs,OpenAIChatModel,OpenAITokenLogprob,OpenAITopLogprob};
+pub use anthropic::chat::{AnthropicChatModel,AnthropicMessageResponse,AnthropicContentBlock,AnthropicUsage};
+pub use anthropic::error::{AnthropicError,AnthropicErrorInner};
 pub use image::{
     ImageCommand,
     ImageResult,
@@ -25,8 +49,24 @@ pub use chat::{
     ChatCommand,
     ChatResult,
     ChatError,
+    ChatBackend,
+    ChatContent,
+    ChatContentPart,
+    ChatImageUrl,
     ChatMessage,
-    ChatRole
+    ChatProvider,
+    ChatRole,
+    ChatSession,
+    ChatStream,
+    ChatStreamHandler,
+    ChatTool,
+    ChatToolType,
+    ChatToolCall,
+    ChatFunction,
+    ChatFunctionCall,
+    OutputFormat,
+    TranscriptFormat,
+    count_tokens
 };
 pub use voice::{
     VoiceCommand,
@@ -1,8 +1,7 @@
 use std::fs;
 use std::concat;
+use std::sync::Arc;
 use clap::{Parser,Subcommand};
-use reqwest::ClientBuilder;
-use reqwest::header::{HeaderValue,HeaderMap};
 use dirs;
 use ai::{
     DEFAULT_CONFIG_FILE,
@@ -18,6 +17,7 @@ use ai::{
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    env_logger::init();
 
     let config_dir = dirs::config_dir()
         .map(|mut path| {
@@ -44,36 +44,55 @@ async fn main() {
         .expect("Config file could not be read");
 
     let config = Config {
+        api_key_anthropic: config_json.api_key_anthropic,
         api_key_cohere: config_json.api_key_cohere,
         api_key_openai: config_json.api_key_openai,
+        api_key_openai_file: config_json.api_key_openai_file,
         api_key_eleven_labs: config_json.api_key_eleven_labs,
-        dir: config_dir
+        api_path_prefix: config_json.api_path_prefix,
+        azure: config_json.azure,
+        base_url: config_json.base_url,
+        cache_ttl_secs: config_json.cache_ttl_secs,
+        dir: config_dir,
+        max_concurrent_requests: config_json.max_concurrent_requests,
+        max_retries: config_json.max_retries,
+        middleware: Vec::new(),
+        organization: config_json.organization,
+        provider: config_json.provider,
+        proxy: config_json.proxy,
+        requests_per_minute: config_json.requests_per_minute,
+        retry_jitter: config_json.retry_jitter,
+        retry_max_delay_secs: config_json.retry_max_delay_secs,
+        stats: Arc::default(),
+        timeout_secs: config_json.timeout_secs,
+        tokens_per_minute: config_json.tokens_per_minute,
+        user: config_json.user
     };
 
-    let mut headers = HeaderMap::new();
-    headers.insert("Accept", HeaderValue::from_static("application/json"));
-    headers.insert("Content-Type", HeaderValue::from_static("application/json"));
-
-    let client = ClientBuilder::new()
-        .default_headers(headers)
-        .build()
-        .expect("Failed to construct http client");
+    let client = match config.client() {
+        Ok(client) => client,
+        Err(error) => {
+            eprintln!("The HTTP client couldn't be set up, check the proxy in {}: {}",
+                config_file.display(), error);
+            std::process::exit(1);
+        }
+    };
 
     match cli.command {
         Commands::Chat(chat) => {
             let result = chat.run(&client, &config).await;
             if let Err(e) = result {
-                eprintln!("{:#?}", e);
+                eprintln!("{}", e);
             }
         },
         Commands::Session(session) => {
             let result = session.run(&client, &config).await;
             if let Err(e) = result {
-                eprintln!("{:#?}", e);
+                eprintln!("{}", e);
             }
         },
         Commands::Image(image) => {
-            let result = image.run(&client).await;
+            let result = image.run(&client, &config).await;
 
             match (result, image.out, image.format) {
                 (Ok(result), None, _p @ PictureFormat::Url) => {
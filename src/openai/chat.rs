@@ -1,23 +1,109 @@
-use crate::chat::{ChatOptions,ChatResult,ChatMessage,ChatMessages,ChatRole,ChatError};
-use std::io::{self,Write};
-use std::env;
+use crate::chat::{
+    ChatBackend,
+    ChatOptions,
+    ChatResult,
+    ChatSession,
+    ChatStream,
+    ChatContent,
+    ChatFunctionCall,
+    ChatMessage,
+    ChatRole,
+    ChatToolCall,
+    ChatToolType,
+    ChatError,
+    OutputFormat,
+    build_messages,
+    content_stream,
+    parse_stream_chunk,
+    strip_prefix_ai
+};
 use async_recursion::async_recursion;
 use serde::{Serialize,Deserialize};
 use reqwest::{Client,RequestBuilder};
 use reqwest_eventsource::{EventSource,Event};
+use clap::ValueEnum;
 use serde_json::json;
+use std::time::Instant;
+use futures_util::future::BoxFuture;
 use futures_util::stream::StreamExt;
 use crate::openai::response::OpenAICompletionResponse;
-use crate::Config;
+use crate::openai::session::OpenAITemperature;
+use crate::openai::{self,OpenAIEndpoint,OPENAI_STOP_SEQUENCES_MAX,OPENAI_TOP_LOGPROBS_MAX};
+use crate::completion::ClashingArgumentsError;
+use crate::session::{Model,ModelFocus};
+use crate::{cache,request,schema,Config};
+
+/// The most follow up requests sent to extend a single truncated response
+const CHAT_CONTINUATIONS_MAX: usize = 5;
+const CHAT_CONTINUE_PROMPT: &str =
+    "Continue exactly where you left off, without repeating anything you already said.";
+const CHAT_TRUNCATED_WARNING: &str = concat!(
+    "warning: The response was cut off because it reached the maximum number of tokens. ",
+    "Raise the token limit, ask the AI to continue or use the continue option.");
 
 pub struct OpenAIChatCommand {
     options: ChatOptions
 }
 
+impl ChatBackend for OpenAIChatCommand {
+    fn run<'a>(&'a mut self, client: &'a Client, config: &'a Config) -> BoxFuture<'a, ChatResult> {
+        OpenAIChatCommand::run(self, client, config)
+    }
+
+    fn stream<'a>(&'a self, client: &'a Client, config: &'a Config)
+        -> BoxFuture<'a, Result<ChatStream, ChatError>>
+    {
+        Box::pin(async move {
+            let options = &self.options;
+            let body = get_request_body(options, true)?;
+            let request = get_request(client, options, config, &body)?;
+            request::throttle(&request, config).await;
+
+            let prefix_ai = (!options.raw).then(|| options.prefix_ai.clone());
+            Ok(content_stream(EventSource::new(request)?, prefix_ai, parse_stream_content))
+        })
+    }
+
+    fn session(&self) -> Result<ChatSession, ChatError> {
+        ChatSession::try_from(&self.options)
+    }
+}
+
 impl TryFrom<ChatOptions> for OpenAIChatCommand {
     type Error = ChatError;
 
     fn try_from(options: ChatOptions) -> Result<Self, Self::Error> {
+        OpenAITemperature::try_from(options.temperature)
+            .map_err(|_| ChatError::TemperatureOutOfValidRange)?;
+
+        if let Some(stop) = &options.completion.stop {
+            if stop.len() > OPENAI_STOP_SEQUENCES_MAX {
+                return Err(ChatError::TooManyStopSequences);
+            }
+        }
+
+        for penalty in [options.presence_penalty, options.frequency_penalty].into_iter().flatten() {
+            OpenAIPenalty::try_from(penalty)?;
+        }
+
+        match options.top_logprobs {
+            Some(top_logprobs) if top_logprobs > OPENAI_TOP_LOGPROBS_MAX => {
+                return Err(ChatError::TopLogprobsOutOfValidRange);
+            },
+            Some(_) if !options.logprobs => {
+                return Err(ChatError::ClashingArguments(ClashingArgumentsError::new(
+                    "Top logprobs can only be requested together with logprobs")));
+            },
+            _ => {}
+        }
+
+        if options.json_mode && options.json_schema.is_some() {
+            return Err(ChatError::ClashingArguments(ClashingArgumentsError::new(
+                "JSON mode and a JSON schema can't be used together, the schema already forces JSON")));
+        }
+
+        options.completion.validate_response_count()?;
+
         Ok(OpenAIChatCommand { options })
     }
 }
@@ -28,8 +114,17 @@ impl OpenAIChatCommand {
         let options = &mut self.options;
         let print_output = !options.completion.quiet.unwrap_or(false);
 
+        // Multiple responses are printed as numbered alternatives once they're all in, so there's
+        // nothing to stream
+        let stream = options.stream && options.completion.response_count.unwrap_or(1) == 1;
+
+        if options.completion.dry_run.unwrap_or(false) {
+            println!("{}", serde_json::to_string_pretty(&get_request_body(options, stream)?)?);
+            return Ok(vec![]);
+        }
+
         loop {
-            if options.stream {
+            if stream {
                 let result = handle_stream(client, options, config).await?;
                 if result.len() > 0 {
                     return Ok(result);
@@ -49,61 +144,277 @@ impl OpenAIChatCommand {
 }
 
 async fn handle_sync(client: &Client, options: &mut ChatOptions, config: &Config, print_output: bool) -> ChatResult {
-    let request = get_request(&client, &options, &config, false)?
-        .send()
-        .await
-        .expect("Failed to send chat");
+    let body = get_request_body(options, false)?;
+    let chat_response = send_sync(client, options, config, &body).await?;
+
+    if options.completion.response_count.is_some_and(|count| count > 1) {
+        return handle_choices(options, chat_response, print_output);
+    }
+
+    let mut choice = chat_response.choices.first().cloned().ok_or(ChatError::NoChoices)?;
+    let mut continuations = 0;
+    while options.continue_truncated && continuations < CHAT_CONTINUATIONS_MAX {
+        if !matches!(choice.finish_reason, Some(OpenAIFinishReason::Length)) {
+            break;
+        }
+        let Some(message) = choice.message.as_mut() else {
+            break;
+        };
 
-    if !request.status().is_success() {
-        return Err(ChatError::OpenAIError(request.json().await?));
+        let mut body = get_request_body(options, false)?;
+        if let Some(messages) = body["messages"].as_array_mut() {
+            messages.push(json!(ChatMessage::new(ChatRole::Ai, message.content.text())));
+            messages.push(json!(ChatMessage::new(ChatRole::User, CHAT_CONTINUE_PROMPT)));
+        }
+
+        let continuation = send_sync(client, options, config, &body).await?
+            .choices
+            .into_iter()
+            .next()
+            .ok_or(ChatError::NoChoices)?;
+
+        if let Some(continued) = continuation.message {
+            message.content = ChatContent::Text(message.content.text() + &continued.content.text());
+        }
+        choice.finish_reason = continuation.finish_reason;
+        continuations += 1;
+    }
+
+    if let Some(OpenAIFinishReason::ContentFilter) = choice.finish_reason {
+        return Err(ChatError::ContentFiltered);
+    }
+
+    if let Some(OpenAIFinishReason::Length) = choice.finish_reason {
+        eprintln!("{}", CHAT_TRUNCATED_WARNING);
     }
 
-    let chat_response: OpenAICompletionResponse<OpenAIChatChoice> = request.json().await?;
-    let text = chat_response.choices.first().unwrap().message
+    let finish_reason = choice.finish_reason.map(|reason| reason.as_str().to_string());
+    if let Some(message) = &choice.message {
+        if has_tool_calls(message) {
+            return Ok(vec![ ChatMessage { finish_reason, ..message.clone() } ]);
+        }
+    }
+
+    let content = choice.message
         .as_ref()
-        .map(|message| {
-            let message = message.content.trim();
+        .map(|message| options.response_content(&message.content.text()));
 
-            if message.to_lowercase().starts_with(&options.prefix_ai) {
-                message.to_string()
-            } else {
-                format!("{}: {}", options.prefix_ai, message)
+    if let Some(content) = content {
+        check_json_schema(options, &content)?;
+
+        let text = options.write_response(&content)?;
+
+        if print_output {
+            match options.format {
+                OutputFormat::Text => options.stream_handler.handle(&format!("{}\n", text))?,
+                OutputFormat::Json => options.stream_handler.handle(&format!("{}\n", json!({
+                    "id": chat_response.id,
+                    "model": chat_response.model,
+                    "created": chat_response.created,
+                    "role": ChatRole::Ai,
+                    "content": choice.message.as_ref().map(|message| message.content.text().trim().to_string()),
+                    "finish_reason": choice.finish_reason,
+                    "logprobs": choice.logprobs,
+                    "usage": chat_response.usage,
+                    "system_fingerprint": chat_response.system_fingerprint
+                })))?
             }
-        });
+        }
+
+        return options.response_result(content, finish_reason);
+    }
+
+    Ok(vec![])
+}
+
+/// Handles the alternatives a request for more than one response gets back. Every choice goes
+/// through the same checks as a single response before any of them is written, then they're
+/// written to the transcript, printed as numbered alternatives and returned
+fn handle_choices(
+    options: &mut ChatOptions,
+    chat_response: OpenAICompletionResponse<OpenAIChatChoice>,
+    print_output: bool) -> ChatResult
+{
+    let choices: Vec<_> = chat_response.choices
+        .iter()
+        .filter_map(|choice| Some((choice, choice.message.as_ref()?)))
+        .collect();
+    if choices.is_empty() {
+        return Err(ChatError::NoChoices);
+    }
+
+    for (choice, message) in &choices {
+        if let Some(OpenAIFinishReason::ContentFilter) = choice.finish_reason {
+            return Err(ChatError::ContentFiltered);
+        }
+        if !has_tool_calls(message) {
+            check_json_schema(options, &options.response_content(&message.content.text()))?;
+        }
+    }
 
-    if let Some(text) = text {
-        let text = options.file.write(text, options.no_context, false)?;
+    if choices.iter().any(|(choice, _)| matches!(choice.finish_reason, Some(OpenAIFinishReason::Length))) {
+        eprintln!("{}", CHAT_TRUNCATED_WARNING);
+    }
+
+    let mut messages = vec![];
+    for (number, (choice, message)) in choices.iter().enumerate() {
+        let finish_reason = choice.finish_reason.map(|reason| reason.as_str().to_string());
+        if has_tool_calls(message) {
+            messages.push(ChatMessage { finish_reason, ..(*message).clone() });
+            continue;
+        }
+
+        let content = options.response_content(&message.content.text());
+        let text = options.write_response(&content)?;
 
         if print_output {
-            println!("{}", text);
+            match options.format {
+                OutputFormat::Text => options.stream_handler.handle(
+                    &format!("Response {} of {}:\n{}\n", number + 1, choices.len(), text))?,
+                OutputFormat::Json => options.stream_handler.handle(&format!("{}\n", json!({
+                    "id": chat_response.id,
+                    "model": chat_response.model,
+                    "created": chat_response.created,
+                    "index": choice.index.unwrap_or(number),
+                    "role": ChatRole::Ai,
+                    "content": content,
+                    "finish_reason": choice.finish_reason,
+                    "logprobs": choice.logprobs,
+                    "system_fingerprint": chat_response.system_fingerprint
+                })))?
+            }
         }
 
-        if options.completion.append.is_some() || options.completion.once.unwrap_or(false) {
-            return Ok(ChatMessages::try_from(&*options)?);
+        messages.push(ChatMessage { finish_reason, ..ChatMessage::new(ChatRole::Ai, content) });
+    }
+
+    Ok(messages)
+}
+
+fn has_tool_calls(message: &ChatMessage) -> bool {
+    message.tool_calls.as_ref().is_some_and(|calls| !calls.is_empty())
+}
+
+/// Sends a non streamed chat completion request and parses the response, printing the token usage
+/// when asked to
+async fn send_sync(
+    client: &Client,
+    options: &ChatOptions,
+    config: &Config,
+    body: &serde_json::Value) -> Result<OpenAICompletionResponse<OpenAIChatChoice>, ChatError>
+{
+    let request = get_request(client, options, config, body)?;
+    let response = match cache::get(config, &request, body) {
+        Some(response) => response,
+        None => {
+            let cache_request = request.try_clone();
+            let request = request::send(request, config)
+                .await
+                .map_err(ChatError::from_request_error)?;
+
+            log::debug!("OpenAI responded with {}", request.status());
+            if !request.status().is_success() {
+                return Err(ChatError::from_openai_error(request.json().await?));
+            }
+
+            let response = request.text().await?;
+            if let Some(cache_request) = cache_request {
+                cache::put(config, &cache_request, body, &response);
+            }
+            response
         }
+    };
+
+    let chat_response: OpenAICompletionResponse<OpenAIChatChoice> =
+        serde_json::from_str(&response)?;
+    log::trace!("Chat response: {:?}", chat_response);
+    if let (true, Some(usage)) = (options.completion.show_usage.unwrap_or(false), &chat_response.usage) {
+        eprintln!("{}", usage);
+    }
+    if let (true, Some(usage)) = (options.completion.show_cost.unwrap_or(false), &chat_response.usage) {
+        eprintln!("{}", usage.cost_summary(&chat_response.model));
+    }
+    if options.completion.show_response_info.unwrap_or(false) {
+        eprintln!("{}", chat_response.info());
     }
 
-    Ok(vec![])
+    Ok(chat_response)
 }
 
 async fn handle_stream(client: &Client, options: &mut ChatOptions, config: &Config) -> ChatResult {
-    let post = get_request(client, options, config, true)?;
-    let mut stream = EventSource::new(post).unwrap();
+    let transcript_len = options.file.transcript.len();
+    let result = stream_response(client, options, config).await;
+    // Writing the response to the transcript replaces the partial response saved while streaming,
+    // every way out that doesn't write it has to take the partial response back out
+    if options.file.transcript.len() == transcript_len {
+        options.discard_partial_response();
+    }
+    result
+}
+
+async fn stream_response(client: &Client, options: &mut ChatOptions, config: &Config) -> ChatResult {
+    let body = get_request_body(options, true)?;
+    let post = get_request(client, options, config, &body)?;
+    request::throttle(&post, config).await;
+    let mut stream = EventSource::new(post)?;
     let mut state = StreamMessageState::New;
     let mut response = String::new();
+    let mut tool_calls = vec![];
+    let mut finish_reason = None;
+    let mut persisted_at = Instant::now();
+
+    'stream: loop {
+        let event = tokio::select! {
+            event = stream.next() => event,
+            _ = options.cancellation.cancelled() => {
+                stream.close();
+                break 'stream;
+            }
+        };
+        let Some(event) = event else {
+            break 'stream;
+        };
 
-    'stream: while let Some(event) = stream.next().await {
         match event {
-            Ok(Event::Open) => {},
-            Ok(Event::Message(message)) if message.data == "[DONE]" => {
+            Ok(Event::Open) => log::debug!("Opened the OpenAI chat stream"),
+            Ok(Event::Message(message)) if message.data.trim() == "[DONE]" => {
+                log::debug!("The OpenAI chat stream is done");
                 break 'stream;
             },
+            Ok(Event::Message(message)) if openai::is_keep_alive(&message.data) => {
+                log::trace!("Skipping a keep alive message in the OpenAI chat stream");
+            },
             Ok(Event::Message(message)) => {
-                state = handle_stream_message(options, message.data, &mut response, state)?;
+                log::trace!("Stream message: {}", message.data);
+                let result = handle_stream_message(
+                    options,
+                    message.data,
+                    &mut response,
+                    &mut tool_calls,
+                    &mut finish_reason,
+                    state);
+                state = match result {
+                    Ok(state) => state,
+                    Err(error) => {
+                        stream.close();
+                        return Err(ChatError::stream_interrupted(options, response, error));
+                    }
+                };
+                options.persist_partial_response(&response, &mut persisted_at);
+            },
+            // Some OpenAI compatible servers close the stream without sending [DONE]
+            Err(reqwest_eventsource::Error::StreamEnded) => break 'stream,
+            Err(reqwest_eventsource::Error::Transport(err)) if err.is_timeout() => {
+                stream.close();
+                return Err(ChatError::stream_interrupted(options, response, ChatError::Timeout));
             },
             Err(err) => {
                 stream.close();
-                return Err(ChatError::EventSource(err));
+                let error = match openai::read_stream_error(err).await {
+                    Ok(error) => ChatError::from_openai_error(error),
+                    Err(err) => ChatError::EventSource(Box::new(err))
+                };
+                return Err(ChatError::stream_interrupted(options, response, error));
             }
         }
     }
@@ -112,37 +423,158 @@ async fn handle_stream(client: &Client, options: &mut ChatOptions, config: &Conf
         StreamMessageState::New => {},
         StreamMessageState::HasWrittenRole |
         StreamMessageState::HasWrittenContent => {
-            println!("");
+            options.stream_handler.handle("\n")?;
             response += "\n";
-            io::stdout().flush().unwrap();
         },
     }
 
-    options.file.write(response, options.no_context, false);
+    let content = response
+        .strip_prefix(&options.ai_label())
+        .unwrap_or(&response)
+        .trim_end()
+        .to_string();
 
-    if options.completion.append.is_some() || options.completion.once.unwrap_or(false) {
-        return Ok(ChatMessages::try_from(&*options)?);
+    if finish_reason.as_deref() == Some("length") {
+        eprintln!("{}", CHAT_TRUNCATED_WARNING);
     }
 
-    Ok(vec![])
+    // Tool calls are returned to the caller to run, like they are without streaming
+    if !tool_calls.is_empty() {
+        return Ok(vec![ ChatMessage {
+            tool_calls: Some(tool_calls),
+            finish_reason,
+            ..ChatMessage::new(ChatRole::Ai, content)
+        } ]);
+    }
+
+    if !options.cancellation.is_cancelled() {
+        check_json_schema(options, &content)?;
+    }
+
+    options.write_response(&content)?;
+
+    options.response_result(content, finish_reason)
 }
 
-fn get_request(client: &Client, options: &ChatOptions, config: &Config, stream: bool) -> Result<RequestBuilder, ChatError> {
-    let messages = ChatMessages::try_from(options)?;
+fn get_request(
+    client: &Client,
+    options: &ChatOptions,
+    config: &Config,
+    body: &serde_json::Value) -> Result<RequestBuilder, ChatError>
+{
+    let request = openai::post(
+        client, config, options.completion.api_key.as_deref(), OpenAIEndpoint::ChatCompletions)?
+        .ok_or(ChatError::Unauthorized)?
+        .json(body);
+
+    log::debug!("Requesting an OpenAI chat completion from {} with {} messages",
+        options.model.to_versioned(), body["messages"].as_array().map_or(0, Vec::len));
+    log::trace!("Request body: {}", body);
 
-    Ok(client.post("https://api.openai.com/v1/chat/completions")
-        .bearer_auth(env::var("OPEN_AI_API_KEY")
-            .ok()
-            .or_else(|| config.api_key_openai.clone())
-            .ok_or_else(|| ChatError::Unauthorized)?
-        )
-        .json(&json!({
-            "model": "gpt-4",
-            "temperature": options.temperature,
-            "messages": messages,
-            "stream": stream
-        }))
-    )
+    Ok(match config.timeout() {
+        Some(timeout) => request.timeout(timeout),
+        None => request
+    })
+}
+
+fn get_request_body(options: &ChatOptions, stream: bool) -> Result<serde_json::Value, ChatError> {
+    let messages = build_messages(options)?;
+    let mut body = json!({
+        "model": options.model.to_versioned(),
+        "messages": messages,
+        "n": options.completion.response_count.unwrap_or(1),
+        "stream": stream
+    });
+
+    let completion = &options.completion;
+    if stream && (completion.show_usage.unwrap_or(false) || completion.show_cost.unwrap_or(false)) {
+        body["stream_options"] = json!({ "include_usage": true });
+    }
+
+    if let Some(max_tokens) = options.max_tokens {
+        body[openai::max_tokens_field(options.model.to_versioned())] = json!(max_tokens);
+    }
+
+    // Reasoning models reject any sampling options other than their defaults
+    if !openai::is_reasoning_model(options.model.to_versioned()) {
+        body["temperature"] = json!(options.temperature);
+
+        if let Some(top_p) = options.completion.top_p {
+            body["top_p"] = json!(top_p);
+        }
+
+        if let Some(presence_penalty) = options.presence_penalty {
+            body["presence_penalty"] = json!(OpenAIPenalty::try_from(presence_penalty)?.0);
+        }
+
+        if let Some(frequency_penalty) = options.frequency_penalty {
+            body["frequency_penalty"] = json!(OpenAIPenalty::try_from(frequency_penalty)?.0);
+        }
+    }
+
+    if let Some(stop) = &options.completion.stop {
+        body["stop"] = json!(stop);
+    }
+
+    if let Some(seed) = openai::seed(options.completion.seed, options.temperature) {
+        body["seed"] = json!(seed);
+    }
+
+    if !options.tools.is_empty() {
+        body["tools"] = json!(options.tools);
+    }
+
+    if !options.logit_bias.is_empty() {
+        body["logit_bias"] = json!(options.logit_bias);
+    }
+
+    if let Some(user) = &options.user {
+        body["user"] = json!(user);
+    }
+
+    if let Some(schema) = &options.json_schema {
+        body["response_format"] = json!({
+            "type": "json_schema",
+            "json_schema": {
+                "name": "response",
+                "strict": true,
+                "schema": schema
+            }
+        });
+    }
+
+    if options.json_mode {
+        let mentions_json = messages
+            .iter()
+            .any(|message| message.content.text().to_lowercase().contains("json"));
+        if !mentions_json {
+            return Err(ChatError::ClashingArguments(ClashingArgumentsError::new(
+                "JSON mode requires the word JSON in the system message or the conversation")));
+        }
+
+        body["response_format"] = json!({ "type": "json_object" });
+    }
+
+    if options.logprobs {
+        body["logprobs"] = json!(true);
+
+        if let Some(top_logprobs) = options.top_logprobs {
+            body["top_logprobs"] = json!(top_logprobs);
+        }
+    }
+
+    Ok(body)
+}
+
+/// Checks the response is JSON matching the schema it was asked to follow, when there is one
+fn check_json_schema(options: &ChatOptions, content: &str) -> Result<(), ChatError> {
+    if let Some(schema) = &options.json_schema {
+        let value = serde_json::from_str(content.trim())
+            .map_err(|error| ChatError::SchemaMismatch(error.to_string()))?;
+        schema::validate(schema, &value).map_err(ChatError::SchemaMismatch)?;
+    }
+
+    Ok(())
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -152,53 +584,279 @@ enum StreamMessageState {
     HasWrittenContent,
 }
 
+/// Reads a streamed chunk, None for a chunk that isn't JSON. OpenAI sends an error that happens
+/// midway through the response as an `{ "error": ... }` chunk
+fn read_stream_chunk(data: &str)
+    -> Result<Option<OpenAICompletionResponse<OpenAIChatDelta>>, ChatError>
+{
+    let Some(chunk) = parse_stream_chunk(data, "OpenAI chat") else {
+        return Ok(None);
+    };
+    if chunk.get("error").is_some() {
+        return Err(ChatError::from_openai_error(serde_json::from_value(chunk)?));
+    }
+
+    Ok(Some(serde_json::from_value(chunk)?))
+}
+
+/// Reads the content delta out of a streamed chunk, None once the stream is done
+fn parse_stream_content(data: &str) -> Result<Option<String>, ChatError> {
+    if data.trim() == "[DONE]" {
+        return Ok(None);
+    }
+    if openai::is_keep_alive(data) {
+        return Ok(Some(String::new()));
+    }
+
+    let Some(chat_response) = read_stream_chunk(data)? else {
+        return Ok(Some(String::new()));
+    };
+    match chat_response.choices.first() {
+        Some(choice) if choice.finish_reason.as_deref() == Some("content_filter") => {
+            Err(ChatError::ContentFiltered)
+        },
+        Some(choice) => Ok(Some(choice.delta.content.clone().unwrap_or_default())),
+        None => Ok(Some(String::new()))
+    }
+}
+
 fn handle_stream_message(
     options: &mut ChatOptions,
     message: String,
     response: &mut String,
+    tool_calls: &mut Vec<ChatToolCall>,
+    finish_reason: &mut Option<String>,
     mut state: StreamMessageState) -> Result<StreamMessageState, ChatError>
 {
-    let chat_response: OpenAICompletionResponse<OpenAIChatDelta> =
-        serde_json::from_str(&message)?;
+    let Some(chat_response) = read_stream_chunk(&message)? else {
+        return Ok(state);
+    };
+
+    if let (true, Some(usage)) = (options.completion.show_usage.unwrap_or(false), &chat_response.usage) {
+        eprintln!("{}", usage);
+    }
+    if let (true, Some(usage)) = (options.completion.show_cost.unwrap_or(false), &chat_response.usage) {
+        eprintln!("{}", usage.cost_summary(&chat_response.model));
+    }
+    if state == StreamMessageState::New && options.completion.show_response_info.unwrap_or(false) {
+        eprintln!("{}", chat_response.info());
+    }
 
-    let delta = &chat_response.choices.first().unwrap().delta;
-    if let Some(ref role) = delta.role {
-        print!("{}", role);
-        response.push_str(&format!("{role}"));
+    // The usage arrives in a chunk of its own without any choices
+    let Some(choice) = chat_response.choices.first() else {
+        return match chat_response.usage {
+            Some(_) => Ok(state),
+            None => Err(ChatError::NoChoices)
+        };
+    };
+    if choice.finish_reason.as_deref() == Some("content_filter") {
+        return Err(ChatError::ContentFiltered);
+    }
+    // Only the last chunk of a choice has a finish reason
+    if choice.finish_reason.is_some() {
+        finish_reason.clone_from(&choice.finish_reason);
+    }
+
+    let delta = &choice.delta;
+    if let Some(deltas) = &delta.tool_calls {
+        accumulate_tool_calls(tool_calls, deltas);
+    }
+    // A response that's only tool calls has no content to label
+    if delta.role.is_some() && delta.tool_calls.is_none() {
+        if !options.raw {
+            let role = options.ai_label();
+            options.stream_handler.handle(&role)?;
+            response.push_str(&role);
+        }
         state = StreamMessageState::HasWrittenRole;
     }
     if let Some(content) = delta.content.clone() {
         let filtered = match state {
+            _ if options.raw => content,
             StreamMessageState::New |
             StreamMessageState::HasWrittenRole => {
                 let filtered = content.trim_start();
-                let prefix_ai = &format!("{}:", options.prefix_ai);
-
-                if filtered.starts_with(prefix_ai) {
-                    filtered
-                        .replacen(prefix_ai, "", 1)
-                        .trim_start()
-                        .to_string()
-                } else {
-                    filtered.to_string()
-                }
+
+                strip_prefix_ai(filtered, &options.prefix_ai)
+                    .unwrap_or(filtered)
+                    .to_string()
             },
             StreamMessageState::HasWrittenContent => content,
         };
 
-        print!("{}", filtered);
+        // The first delta is often empty, the label the model repeats can still follow it
+        if filtered.is_empty() {
+            return Ok(state);
+        }
+
+        options.stream_handler.handle(&filtered)?;
         state = StreamMessageState::HasWrittenContent;
         response.push_str(&filtered);
     }
-    io::stdout().flush().unwrap();
     Ok(state)
 }
 
+/// Adds the fragments of streamed tool calls to the calls they belong to. The id, type and name
+/// arrive in the first fragment of a call, the arguments are spread over the following ones.
+/// Calls are numbered in order, so a fragment that skips ahead of the next call is malformed and
+/// skipped
+fn accumulate_tool_calls(tool_calls: &mut Vec<ChatToolCall>, deltas: &[ChatToolCallDelta]) {
+    for delta in deltas {
+        if delta.index > tool_calls.len() {
+            log::warn!(
+                "Skipping a tool call fragment for call {} in the OpenAI chat stream, which has {} calls",
+                delta.index,
+                tool_calls.len());
+            continue;
+        }
+
+        if delta.index == tool_calls.len() {
+            tool_calls.push(ChatToolCall {
+                id: String::new(),
+                r#type: ChatToolType::Function,
+                function: ChatFunctionCall { name: String::new(), arguments: String::new() }
+            });
+        }
+
+        let tool_call = &mut tool_calls[delta.index];
+        if let Some(id) = &delta.id {
+            tool_call.id.push_str(id);
+        }
+        if let Some(r#type) = delta.r#type {
+            tool_call.r#type = r#type;
+        }
+        if let Some(function) = &delta.function {
+            let ChatFunctionCallDelta { name, arguments } = function;
+            tool_call.function.name.push_str(name.as_deref().unwrap_or_default());
+            tool_call.function.arguments.push_str(arguments.as_deref().unwrap_or_default());
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OpenAIPenalty(pub f32);
+
+impl TryFrom<f32> for OpenAIPenalty {
+    type Error = ChatError;
+
+    fn try_from(n: f32) -> Result<Self, ChatError> {
+        if n.is_finite() && (-2.0..=2.0).contains(&n) {
+            Ok(OpenAIPenalty(n))
+        } else {
+            Err(ChatError::PenaltyOutOfValidRange)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum OpenAIChatModel {
+    /// OpenAI's gpt-4 model
+    #[default]
+    #[value(name = "gpt-4")]
+    #[serde(rename = "gpt-4")]
+    Gpt4,
+
+    /// OpenAI's gpt-4-turbo model, a faster gpt-4 with a larger context and vision
+    #[value(name = "gpt-4-turbo")]
+    #[serde(rename = "gpt-4-turbo")]
+    Gpt4Turbo,
+
+    /// OpenAI's gpt-4o model, multimodal and cheaper than gpt-4-turbo
+    #[value(name = "gpt-4o")]
+    #[serde(rename = "gpt-4o")]
+    Gpt4o,
+
+    /// OpenAI's gpt-3.5-turbo model, cheaper and faster than gpt-4
+    #[value(name = "gpt-3.5-turbo")]
+    #[serde(rename = "gpt-3.5-turbo")]
+    Gpt35Turbo,
+
+    /// OpenAI's o1 reasoning model, which thinks before it answers
+    #[value(name = "o1")]
+    #[serde(rename = "o1")]
+    O1,
+
+    /// OpenAI's o1-mini reasoning model, cheaper and faster than o1
+    #[value(name = "o1-mini")]
+    #[serde(rename = "o1-mini")]
+    O1Mini,
+}
+
+impl TryFrom<(ModelFocus, Model)> for OpenAIChatModel {
+    type Error = ChatError;
+
+    fn try_from(models: (ModelFocus, Model)) -> Result<OpenAIChatModel, ChatError> {
+        // The chat models are all general purpose and larger than the small completion models,
+        // so the smaller sizes share gpt-3.5-turbo and there's no small model for code
+        Ok(match models {
+            (ModelFocus::Code, Model::Tiny) |
+            (ModelFocus::Code, Model::Small) => return Err(ChatError::NoMatchingModel),
+            (ModelFocus::Text, Model::Tiny) => {
+                eprintln!(concat!(
+                    "warning: No exact matching OpenAI chat model for the tiny option. ",
+                    "Falling back to gpt-3.5-turbo."));
+                OpenAIChatModel::Gpt35Turbo
+            },
+            (_, Model::Small) |
+            (_, Model::Medium) |
+            (_, Model::Large) => OpenAIChatModel::Gpt35Turbo,
+            (_, Model::XLarge) => OpenAIChatModel::Gpt4o,
+            (_, Model::XXLarge) => OpenAIChatModel::Gpt4,
+        })
+    }
+}
+
+impl OpenAIChatModel {
+    pub fn to_versioned(&self) -> &str {
+        match self {
+            OpenAIChatModel::Gpt4 => "gpt-4",
+            OpenAIChatModel::Gpt4Turbo => "gpt-4-turbo",
+            OpenAIChatModel::Gpt4o => "gpt-4o",
+            OpenAIChatModel::Gpt35Turbo => "gpt-3.5-turbo",
+            OpenAIChatModel::O1 => "o1",
+            OpenAIChatModel::O1Mini => "o1-mini",
+        }
+    }
+}
+
+impl TryFrom<&str> for OpenAIChatModel {
+    type Error = ChatError;
+
+    fn try_from(model: &str) -> Result<Self, Self::Error> {
+        <OpenAIChatModel as ValueEnum>::from_str(model.trim(), true)
+            .map_err(|_| ChatError::UnknownModel(model.to_string()))
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OpenAIChatChoice {
     index: Option<usize>,
-    message: Option<ChatMessage>,
-    finish_reason: Option<OpenAIFinishReason>
+    pub(crate) message: Option<ChatMessage>,
+    finish_reason: Option<OpenAIFinishReason>,
+    logprobs: Option<OpenAIChatLogprobs>
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OpenAIChatLogprobs {
+    #[serde(default)]
+    pub content: Option<Vec<OpenAITokenLogprob>>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpenAITokenLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Option<Vec<u8>>,
+    /// The most likely alternatives for this token, when top_logprobs was requested
+    #[serde(default)]
+    pub top_logprobs: Vec<OpenAITopLogprob>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OpenAITopLogprob {
+    pub token: String,
+    pub logprob: f32,
+    pub bytes: Option<Vec<u8>>
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
@@ -206,20 +864,62 @@ pub struct OpenAIChatChoice {
 pub enum OpenAIFinishReason {
     Stop,
     Length,
-    ContentFilter
+    ContentFilter,
+    ToolCalls,
+    FunctionCall
+}
+
+impl OpenAIFinishReason {
+    pub fn as_str(&self) -> &str {
+        match self {
+            OpenAIFinishReason::Stop => "stop",
+            OpenAIFinishReason::Length => "length",
+            OpenAIFinishReason::ContentFilter => "content_filter",
+            OpenAIFinishReason::ToolCalls => "tool_calls",
+            OpenAIFinishReason::FunctionCall => "function_call",
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct OpenAIChatDelta {
     index: Option<usize>,
-    delta: ChatMessageDelta,
+    pub(crate) delta: ChatMessageDelta,
     finish_reason: Option<String>
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ChatMessageDelta {
+    #[serde(default, deserialize_with = "deserialize_delta_role")]
     pub role: Option<ChatRole>,
     pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ChatToolCallDelta>>,
+}
+
+/// A fragment of a streamed tool call, index says which of the response's tool calls it's part of
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ChatToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    pub r#type: Option<ChatToolType>,
+    pub function: Option<ChatFunctionCallDelta>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ChatFunctionCallDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+/// Streamed deltas are always part of the AI's response, so a role ChatRole doesn't know about is
+/// read as the AI instead of failing the whole stream
+fn deserialize_delta_role<'de, D>(deserializer: D) -> Result<Option<ChatRole>, D::Error>
+where
+    D: serde::Deserializer<'de>
+{
+    let role = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(role.map(|role| serde_json::from_value(role).unwrap_or(ChatRole::Ai)))
 }
 
 #[cfg(test)]
@@ -227,13 +927,18 @@ mod tests {
     use super::*;
     use crate::chat::*;
     use crate::completion::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc,Mutex};
 
     #[test]
     fn transcript_with_multiple_lines() {
         let system = String::from("You're a duck. Say quack.");
         let file = CompletionFile {
-            file: None,
+            path: None,
+            header: String::new(),
             overrides: ChatCommand::default(),
+            last_read_input: String::new(),
+            last_written_input: String::new(),
             transcript: concat!(
                 "USER: hey\n",
                 concat!(
@@ -263,8 +968,11 @@ mod tests {
     fn transcript_handles_labels_correctly() {
         let system = String::from("You're a duck. Say quack.");
         let file = CompletionFile {
-            file: None,
+            path: None,
+            header: String::new(),
             overrides: ChatCommand::default(),
+            last_read_input: String::new(),
+            last_written_input: String::new(),
             transcript: concat!(
                 "USER: hey\n",
                 concat!(
@@ -290,12 +998,83 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn transcript_round_trips_with_custom_prefixes() {
+        let transcript = concat!(
+            "Human: hey\n",
+            "Assistant: Quack.\n",
+            "Ducks don't talk\n",
+            "Human: AI: is this a label?\n"
+        );
+        let file = CompletionFile {
+            path: None,
+            header: String::new(),
+            overrides: ChatCommand::default(),
+            last_read_input: String::new(),
+            last_written_input: String::new(),
+            transcript: transcript.to_string()
+        };
+        let options = ChatOptions {
+            prefix_ai: "Assistant".into(),
+            prefix_user: "Human".into(),
+            tokens_max: 4096,
+            tokens_balance: 0.5,
+            file,
+            ..ChatOptions::default()
+        };
+        let messages = parse_transcript(&options).unwrap();
+        assert_eq!(messages, vec![
+            ChatMessage::new(ChatRole::User, "hey"),
+            ChatMessage::new(ChatRole::Ai, "Quack.\nDucks don't talk"),
+            ChatMessage::new(ChatRole::User, "AI: is this a label?"),
+        ]);
+
+        let session = ChatSession {
+            provider: ChatProvider::OpenAI,
+            model: "gpt-4".into(),
+            temperature: 0.8,
+            system: String::new(),
+            tokens: 0,
+            messages
+        };
+        assert_eq!(session.transcript("Assistant", "Human"), transcript);
+    }
+
     #[test]
     fn transcript_labotomizes_itself() {
         let system = String::from("You're a duck. Say quack.");
         let file = CompletionFile {
-            file: None,
+            path: None,
+            header: String::new(),
+            overrides: ChatCommand::default(),
+            last_read_input: String::new(),
+            last_written_input: String::new(),
+            transcript: concat!(
+                "USER: hey. This is a really long message to ensure that it gets labotomized.\n",
+                "AI: hey"
+            ).to_string()
+        };
+        let options = ChatOptions {
+            tokens_max: 40,
+            tokens_balance: 0.5,
+            system: system.clone(),
+            file,
+            ..ChatOptions::default()
+        };
+        assert_eq!(ChatMessages::try_from(&options).unwrap(), vec![
+            ChatMessage::new(ChatRole::System, system),
+            ChatMessage::new(ChatRole::Ai, "hey"),
+        ]);
+    }
+
+    #[test]
+    fn dropped_messages_are_warned_about_once() {
+        let file = CompletionFile {
+            path: None,
+            header: String::new(),
             overrides: ChatCommand::default(),
+            last_read_input: String::new(),
+            last_written_input: String::new(),
             transcript: concat!(
                 "USER: hey. This is a really long message to ensure that it gets labotomized.\n",
                 "AI: hey"
@@ -304,22 +1083,136 @@ mod tests {
         let options = ChatOptions {
             tokens_max: 40,
             tokens_balance: 0.5,
+            system: String::from("You're a duck. Say quack."),
+            file,
+            ..ChatOptions::default()
+        };
+        assert_eq!(
+            Some("warning: Dropped the 1 oldest messages (20 tokens) to fit the conversation in 20 of the 40 maximum tokens."),
+            options.dropped_messages_warning(1, 20).as_deref());
+        assert_eq!(None, options.dropped_messages_warning(1, 20));
+
+        // Building the messages again for the next request doesn't repeat it
+        let options = ChatOptions {
+            tokens_max: 40,
+            tokens_balance: 0.5,
+            system: options.system,
+            file: options.file,
+            ..ChatOptions::default()
+        };
+        ChatMessages::try_from(&options).unwrap();
+        ChatMessages::try_from(&options).unwrap();
+        assert_eq!(None, options.dropped_messages_warning(1, 20));
+    }
+
+    #[test]
+    fn transcript_keeps_the_system_and_latest_message_when_trimming() {
+        let system = String::from("You're a duck. Say quack.");
+        let file = CompletionFile {
+            path: None,
+            header: String::new(),
+            overrides: ChatCommand::default(),
+            last_read_input: String::new(),
+            last_written_input: String::new(),
+            transcript: concat!(
+                "AI: hey\n",
+                "USER: hey. This is a really long message to ensure that it gets labotomized.\n",
+            ).to_string()
+        };
+        let mut options = ChatOptions {
+            tokens_max: 40,
+            tokens_balance: 0.5,
+            system: system.clone(),
+            file,
+            ..ChatOptions::default()
+        };
+        assert_eq!(ChatMessages::try_from(&options).unwrap(), vec![
+            ChatMessage::new(ChatRole::System, system),
+            ChatMessage::new(ChatRole::User,
+                "hey. This is a really long message to ensure that it gets labotomized."),
+        ]);
+
+        options.tokens_max = 20;
+        assert!(matches!(
+            ChatMessages::try_from(&options),
+            Err(ChatError::ContextTooLarge { tokens_max: 20, .. })));
+    }
+
+    #[test]
+    fn transcript_line_endings_are_normalized() {
+        let mut file = CompletionFile::<ChatCommand>::default();
+        file.write("USER: hey\r\nthere".into(), false, true).unwrap();
+        file.write_words("AI: quack\r\n".into()).unwrap();
+
+        assert_eq!("USER: hey\nthere\nAI: quack\n", file.transcript);
+    }
+
+    #[test]
+    fn transcript_never_trims_the_examples() {
+        let system = String::from("You're a duck. Say quack.");
+        let file = CompletionFile {
+            path: None,
+            header: String::new(),
+            overrides: ChatCommand::default(),
+            last_read_input: String::new(),
+            last_written_input: String::new(),
+            transcript: concat!(
+                "USER: hey. This is a really long message to ensure that it gets labotomized.\n",
+                "AI: hey"
+            ).to_string()
+        };
+        let examples = vec![
+            ChatMessage::new(ChatRole::User, "Hello"),
+            ChatMessage::new(ChatRole::Ai, "Quack")
+        ];
+        let options = ChatOptions {
+            tokens_max: 50,
+            tokens_balance: 0.5,
             system: system.clone(),
+            examples: examples.clone(),
             file,
             ..ChatOptions::default()
         };
         assert_eq!(ChatMessages::try_from(&options).unwrap(), vec![
             ChatMessage::new(ChatRole::System, system),
+            examples[0].clone(),
+            examples[1].clone(),
             ChatMessage::new(ChatRole::Ai, "hey"),
         ]);
     }
 
+    #[test]
+    fn transcript_system_message_replaces_the_configured_one() {
+        let file = CompletionFile {
+            path: None,
+            header: String::new(),
+            overrides: ChatCommand::default(),
+            last_read_input: String::new(),
+            last_written_input: String::new(),
+            transcript: "SYSTEM: You're a goose.\nUSER: hey\n".to_string()
+        };
+        let options = ChatOptions {
+            tokens_max: 4096,
+            tokens_balance: 0.5,
+            system: "You're a duck.".into(),
+            file,
+            ..ChatOptions::default()
+        };
+        assert_eq!(ChatMessages::try_from(&options).unwrap(), vec![
+            ChatMessage::new(ChatRole::System, "You're a goose."),
+            ChatMessage::new(ChatRole::User, "hey"),
+        ]);
+    }
+
     #[test]
     fn streaming_strips_whitespace_and_labels_from_delta_content() {
         let file = CompletionFile {
-            file: None,
+            path: None,
+            header: String::new(),
             overrides: ChatCommand::default(),
-            transcript: String::new()
+            transcript: String::new(),
+            last_read_input: String::new(),
+            last_written_input: String::new()
         };
         let mut options = ChatOptions {
             tokens_max: 40,
@@ -343,10 +1236,464 @@ mod tests {
             "id": ""
         }"#);
 
-        let state = handle_stream_message(&mut options, chat_response, StreamMessageState::New)
+        let mut response = String::new();
+        let state = handle_stream_message(
+            &mut options,
+            chat_response,
+            &mut response,
+            &mut vec![],
+            &mut None,
+            StreamMessageState::New)
             .unwrap();
 
         assert_eq!(StreamMessageState::HasWrittenContent, state);
-        assert_eq!("AI: hey there", &options.file.transcript)
+        assert_eq!("AI: hey there", &response)
+    }
+
+    #[test]
+    fn streamed_tool_calls_are_assembled_from_their_fragments() {
+        let mut options = ChatOptions { prefix_ai: "AI".into(), ..ChatOptions::default() };
+        let chunks = [
+            r#"{"id": "", "object": "", "created": 0, "model": "", "choices": [{ "delta": {
+                "role": "assistant",
+                "tool_calls": [{ "index": 0, "id": "call_1", "type": "function",
+                    "function": { "name": "quack", "arguments": "" } }]
+            } }]}"#,
+            r#"{"id": "", "object": "", "created": 0, "model": "", "choices": [{ "delta": {
+                "tool_calls": [{ "index": 0, "function": { "arguments": "{\"times\"" } }]
+            } }]}"#,
+            r#"{"id": "", "object": "", "created": 0, "model": "", "choices": [{ "delta": {
+                "tool_calls": [{ "index": 0, "function": { "arguments": ": 2}" } }]
+            } }]}"#,
+        ];
+
+        let mut response = String::new();
+        let mut tool_calls = vec![];
+        let mut state = StreamMessageState::New;
+        for chunk in chunks {
+            state = handle_stream_message(
+                &mut options, chunk.into(), &mut response, &mut tool_calls, &mut None, state)
+                .unwrap();
+        }
+
+        assert_eq!("", &response);
+        assert_eq!(vec![ChatToolCall {
+            id: "call_1".into(),
+            r#type: ChatToolType::Function,
+            function: ChatFunctionCall { name: "quack".into(), arguments: "{\"times\": 2}".into() }
+        }], tool_calls);
+    }
+
+    #[test]
+    fn tool_call_fragments_that_skip_ahead_are_skipped() {
+        let delta = |index, name: &str| ChatToolCallDelta {
+            index,
+            function: Some(ChatFunctionCallDelta { name: Some(name.into()), arguments: None }),
+            ..ChatToolCallDelta::default()
+        };
+
+        let mut tool_calls = vec![];
+        accumulate_tool_calls(&mut tool_calls, &[delta(0, "quack"), delta(1_000_000_000, "moo")]);
+        accumulate_tool_calls(&mut tool_calls, &[delta(1, "honk")]);
+
+        let names: Vec<_> = tool_calls.iter().map(|call| call.function.name.as_str()).collect();
+        assert_eq!(vec!["quack", "honk"], names);
+    }
+
+    #[test]
+    fn keep_alive_messages_are_recognized() {
+        assert!(openai::is_keep_alive(""));
+        assert!(openai::is_keep_alive("  \n"));
+        assert!(openai::is_keep_alive(": ping"));
+        assert!(!openai::is_keep_alive("[DONE]"));
+        assert!(!openai::is_keep_alive(r#"{"choices": []}"#));
+    }
+
+    #[test]
+    fn streams_ask_for_the_usage_when_it_is_shown() {
+        let options = |show_usage| ChatOptions {
+            model: OpenAIChatModel::Gpt4o,
+            tokens_max: 4096,
+            tokens_balance: 0.5,
+            completion: CompletionOptions { show_usage, ..CompletionOptions::default() },
+            ..ChatOptions::default()
+        };
+
+        let body = get_request_body(&options(Some(true)), true).unwrap();
+        assert_eq!(json!({ "include_usage": true }), body["stream_options"]);
+        assert!(get_request_body(&options(Some(true)), false).unwrap()["stream_options"].is_null());
+        assert!(get_request_body(&options(None), true).unwrap()["stream_options"].is_null());
+    }
+
+    #[test]
+    fn stream_usage_chunks_without_choices_are_accepted() {
+        let mut options = ChatOptions { prefix_ai: "AI".into(), ..ChatOptions::default() };
+        let mut response = String::from("AI: quack");
+        let chunk = json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [],
+            "usage": { "prompt_tokens": 5, "completion_tokens": 1, "total_tokens": 6 }
+        });
+
+        let state = handle_stream_message(
+            &mut options,
+            chunk.to_string(),
+            &mut response,
+            &mut vec![],
+            &mut None,
+            StreamMessageState::HasWrittenContent).unwrap();
+
+        assert_eq!(StreamMessageState::HasWrittenContent, state);
+        assert_eq!("AI: quack", &response)
+    }
+
+    #[test]
+    fn malformed_stream_chunks_are_skipped() {
+        let mut options = ChatOptions { prefix_ai: "AI".into(), ..ChatOptions::default() };
+        let mut response = String::new();
+
+        let state = handle_stream_message(
+            &mut options,
+            "{\"choices\": [".into(),
+            &mut response,
+            &mut vec![],
+            &mut None,
+            StreamMessageState::HasWrittenRole).unwrap();
+
+        assert_eq!(StreamMessageState::HasWrittenRole, state);
+        assert_eq!("", &response)
+    }
+
+    #[test]
+    fn stream_error_chunks_end_the_stream() {
+        let mut options = ChatOptions { prefix_ai: "AI".into(), ..ChatOptions::default() };
+        let error = r#"{"error": {"message": "The server had an error", "type": "server_error", "param": null, "code": null}}"#;
+
+        let result = handle_stream_message(
+            &mut options,
+            error.into(),
+            &mut String::new(),
+            &mut vec![],
+            &mut None,
+            StreamMessageState::HasWrittenContent);
+        assert!(matches!(result, Err(ChatError::OpenAIError(_))));
+        assert!(matches!(parse_stream_content(error), Err(ChatError::OpenAIError(_))));
+        assert_eq!(Some(String::new()), parse_stream_content("{\"choices\": [").unwrap());
+    }
+
+    #[test]
+    fn stream_handler_receives_the_streamed_response() {
+        let streamed = Arc::new(Mutex::new(String::new()));
+        let handler_streamed = streamed.clone();
+        let mut options = ChatOptions {
+            prefix_ai: "AI".into(),
+            stream_handler: ChatStreamHandler::new(move |text| {
+                handler_streamed.lock().unwrap().push_str(text);
+                Ok(())
+            }),
+            ..ChatOptions::default()
+        };
+        let chat_response = String::from(r#"{
+            "choices": [{ "delta": { "role": "assistant", "content": "hey there" } }],
+            "created": 0,
+            "model": "",
+            "object": "",
+            "id": ""
+        }"#);
+
+        let mut response = String::new();
+        handle_stream_message(
+            &mut options,
+            chat_response,
+            &mut response,
+            &mut vec![],
+            &mut None,
+            StreamMessageState::New)
+            .unwrap();
+
+        assert_eq!("AI: hey there", &*streamed.lock().unwrap());
+    }
+
+    #[test]
+    fn tokens_are_counted_with_the_chat_model_encoding() {
+        assert_eq!(2, count_tokens("hello world"));
+        assert_eq!(0, count_tokens(""));
+    }
+
+    #[test]
+    fn responses_are_checked_against_the_json_schema() {
+        let options = ChatOptions {
+            json_schema: Some(json!({
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string" },
+                    "legs": { "type": "integer" }
+                },
+                "required": ["name", "legs"],
+                "additionalProperties": false
+            })),
+            ..ChatOptions::default()
+        };
+
+        assert!(check_json_schema(&options, r#"{ "name": "duck", "legs": 2 }"#).is_ok());
+        assert!(check_json_schema(&options, r#"{ "name": "duck" }"#).is_err());
+        assert!(check_json_schema(&options, r#"{ "name": "duck", "legs": "two" }"#).is_err());
+        assert!(check_json_schema(&options, r#"{ "name": "duck", "legs": 2, "wings": 2 }"#).is_err());
+        assert!(check_json_schema(&options, "quack").is_err());
+    }
+
+    #[test]
+    fn sessions_round_trip_through_json() {
+        let session = ChatSession {
+            provider: ChatProvider::OpenAI,
+            model: "gpt-4".into(),
+            temperature: 0.8,
+            system: "You're a duck.".into(),
+            tokens: 0,
+            messages: vec![
+                ChatMessage::new(ChatRole::User, "hey"),
+                ChatMessage::new(ChatRole::Ai, "quack\nquack"),
+            ]
+        };
+        let path = std::env::temp_dir().join(format!("ai-session-{}.json", std::process::id()));
+
+        session.save_json(&path).unwrap();
+        let loaded = ChatSession::load_json(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(session, loaded);
+        assert_eq!("DUCK: hey\nAI: quack\nquack\n", loaded.transcript("AI", "DUCK"));
+    }
+
+    #[test]
+    fn sessions_are_truncated_to_whole_exchanges() {
+        let messages = vec![
+            ChatMessage::new(ChatRole::System, "You're a duck."),
+            ChatMessage::new(ChatRole::User, "hey"),
+            ChatMessage::new(ChatRole::Ai, "quack"),
+            ChatMessage::new(ChatRole::User, "again"),
+            ChatMessage::new(ChatRole::Ai, "quack quack"),
+        ];
+        let mut session = ChatSession {
+            provider: ChatProvider::OpenAI,
+            model: "gpt-4".into(),
+            temperature: 0.8,
+            system: String::new(),
+            tokens: messages.iter().map(|message| message.tokens).sum(),
+            messages: messages.clone()
+        };
+
+        session.truncate(2);
+        assert_eq!(messages, session.messages);
+
+        session.truncate(1);
+        assert_eq!(messages[..3], session.messages[..]);
+        assert_eq!(messages[..3].iter().map(|message| message.tokens).sum::<usize>(), session.tokens);
+
+        session.truncate(0);
+        assert_eq!(messages[..1], session.messages[..]);
+    }
+
+    #[test]
+    fn sessions_without_a_token_count_are_truncated() {
+        let mut session = ChatSession {
+            provider: ChatProvider::OpenAI,
+            model: "gpt-4".into(),
+            temperature: 0.8,
+            system: String::new(),
+            tokens: 0,
+            messages: vec![
+                ChatMessage::new(ChatRole::User, "hey"),
+                ChatMessage::new(ChatRole::Ai, "quack"),
+            ]
+        };
+
+        session.truncate(0);
+        assert!(session.messages.is_empty());
+        assert_eq!(0, session.tokens);
+    }
+
+    #[test]
+    fn chat_models_are_parsed_from_their_names() {
+        assert_eq!(OpenAIChatModel::Gpt4o, OpenAIChatModel::try_from("gpt-4o").unwrap());
+        assert_eq!(OpenAIChatModel::Gpt4Turbo, OpenAIChatModel::try_from("gpt-4-turbo").unwrap());
+        assert!(matches!(
+            OpenAIChatModel::try_from("gpt-4-trubo"),
+            Err(ChatError::UnknownModel(model)) if model == "gpt-4-trubo"));
+    }
+
+    #[test]
+    fn chat_models_are_picked_by_size_and_focus() {
+        let model = |focus, size| OpenAIChatModel::try_from((focus, size));
+        assert_eq!(OpenAIChatModel::Gpt4, model(ModelFocus::Text, Model::XXLarge).unwrap());
+        assert_eq!(OpenAIChatModel::Gpt4o, model(ModelFocus::Code, Model::XLarge).unwrap());
+        assert_eq!(OpenAIChatModel::Gpt35Turbo, model(ModelFocus::Text, Model::Small).unwrap());
+        assert!(matches!(model(ModelFocus::Code, Model::Small), Err(ChatError::NoMatchingModel)));
+    }
+
+    #[test]
+    fn reasoning_models_are_sent_max_completion_tokens() {
+        let options = |model| ChatOptions {
+            model,
+            max_tokens: Some(100),
+            temperature: 0.8,
+            presence_penalty: Some(0.5),
+            completion: CompletionOptions { top_p: Some(0.9), ..CompletionOptions::default() },
+            tokens_max: 4096,
+            tokens_balance: 0.5,
+            ..ChatOptions::default()
+        };
+
+        let body = get_request_body(&options(OpenAIChatModel::O1Mini), false).unwrap();
+        assert_eq!(json!(100), body["max_completion_tokens"]);
+        assert!(body["max_tokens"].is_null());
+        assert!(body["temperature"].is_null());
+        assert!(body["top_p"].is_null());
+        assert!(body["presence_penalty"].is_null());
+
+        let body = get_request_body(&options(OpenAIChatModel::Gpt4o), false).unwrap();
+        assert_eq!(json!(100), body["max_tokens"]);
+        assert!(body["max_completion_tokens"].is_null());
+        assert!(body["temperature"].is_number());
+        assert!(body["top_p"].is_number());
+        assert!(body["presence_penalty"].is_number());
+    }
+
+    #[test]
+    fn zero_temperature_requests_are_seeded() {
+        let options = |temperature, seed| ChatOptions {
+            model: OpenAIChatModel::Gpt4o,
+            temperature,
+            tokens_max: 4096,
+            tokens_balance: 0.5,
+            completion: CompletionOptions { seed, ..CompletionOptions::default() },
+            ..ChatOptions::default()
+        };
+
+        let body = get_request_body(&options(0.0, None), false).unwrap();
+        assert_eq!(json!(openai::OPENAI_ZERO_TEMPERATURE_SEED), body["seed"]);
+
+        let body = get_request_body(&options(0.0, Some(42)), false).unwrap();
+        assert_eq!(json!(42), body["seed"]);
+
+        let body = get_request_body(&options(0.8, None), false).unwrap();
+        assert!(body["seed"].is_null());
+    }
+
+    #[test]
+    fn logit_biases_are_sent_by_token_id() {
+        let options = ChatOptions {
+            logit_bias: HashMap::from([(50256, -100.0)]),
+            tokens_max: 4096,
+            tokens_balance: 0.5,
+            ..ChatOptions::default()
+        };
+
+        let body = get_request_body(&options, false).unwrap();
+        assert_eq!(json!({ "50256": -100.0 }), body["logit_bias"]);
+    }
+
+    #[test]
+    fn unknown_delta_roles_are_read_as_the_ai() {
+        let delta: ChatMessageDelta =
+            serde_json::from_str(r#"{ "role": "narrator", "content": "hey" }"#).unwrap();
+        assert_eq!(Some(ChatRole::Ai), delta.role);
+
+        let delta: ChatMessageDelta = serde_json::from_str(r#"{ "content": "hey" }"#).unwrap();
+        assert_eq!(None, delta.role);
+    }
+
+    #[test]
+    fn prefix_stripping_ignores_label_case_but_keeps_content_case() {
+        assert_eq!(Some("Hey There"), strip_prefix_ai("  ai: Hey There", "AI"));
+        assert_eq!(Some("Hey There"), strip_prefix_ai("Ai:Hey There", "AI"));
+        assert_eq!(None, strip_prefix_ai("AIR: Hey There", "AI"));
+        assert_eq!(None, strip_prefix_ai("Hey There", "AI"));
+    }
+
+    #[test]
+    fn chat_roles_serialize_to_the_api_role_names() {
+        let roles = [
+            (ChatRole::Ai, "assistant"),
+            (ChatRole::User, "user"),
+            (ChatRole::System, "system"),
+            (ChatRole::Tool, "tool")
+        ];
+        for (role, name) in roles {
+            assert_eq!(json!(name), serde_json::to_value(role).unwrap());
+            assert_eq!(role, serde_json::from_value::<ChatRole>(json!(name)).unwrap());
+        }
+
+        let options = ChatOptions {
+            system: String::from("You're a duck."),
+            file: CompletionFile {
+                path: None,
+                header: String::new(),
+                overrides: ChatCommand::default(),
+                last_read_input: String::new(),
+                last_written_input: String::new(),
+                transcript: String::from("USER: hey\nAI: quack\nUSER: quack?")
+            },
+            tokens_max: 4096,
+            tokens_balance: 0.5,
+            ..ChatOptions::default()
+        };
+        let body = get_request_body(&options, false).unwrap();
+        let sent: Vec<_> = body["messages"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|message| message["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["system", "user", "assistant", "user"], sent);
+    }
+
+    #[test]
+    fn insufficient_quota_errors_are_told_apart_from_rate_limits() {
+        let error = |code| serde_json::from_value::<openai::OpenAIError>(json!({
+            "error": { "message": "quack", "type": "requests", "param": null, "code": code }
+        })).unwrap();
+
+        assert!(matches!(
+            ChatError::from_openai_error(error("insufficient_quota")),
+            ChatError::QuotaExceeded(_)));
+        assert!(matches!(
+            ChatError::from_openai_error(error("rate_limit_exceeded")),
+            ChatError::OpenAIError(_)));
+    }
+
+    #[test]
+    fn message_names_are_only_sent_when_present() {
+        let message = ChatMessage::new(ChatRole::User, "hey");
+        assert!(serde_json::to_value(&message).unwrap().get("name").is_none());
+
+        let message = ChatMessage { name: Some("mallard".into()), ..message };
+        assert_eq!(json!("mallard"), serde_json::to_value(&message).unwrap()["name"]);
+    }
+
+    #[test]
+    fn tool_call_responses_without_content_are_read_as_empty_text() {
+        for content in [r#""content": null,"#, ""] {
+            let choice: OpenAIChatChoice = serde_json::from_str(&format!(r#"{{
+                "index": 0,
+                "message": {{
+                    "role": "assistant",
+                    {}
+                    "tool_calls": [{{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": {{ "name": "quack", "arguments": "{{}}" }}
+                    }}]
+                }},
+                "finish_reason": "tool_calls"
+            }}"#, content)).unwrap();
+
+            let message = choice.message.unwrap();
+            assert_eq!(ChatContent::Text(String::new()), message.content);
+            assert_eq!(1, message.tool_calls.unwrap().len());
+        }
     }
 }
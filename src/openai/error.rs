@@ -1,5 +1,6 @@
 use serde::Deserialize;
 
+/// The error body returned by OpenAI, `{ "error": { "message", "type", "param", "code" } }`
 #[derive(Deserialize, Debug, Clone)]
 pub struct OpenAIError {
     pub error: OpenAIErrorInner
@@ -8,7 +9,34 @@ pub struct OpenAIError {
 #[derive(Deserialize, Debug, Clone)]
 pub struct OpenAIErrorInner {
     pub message: String,
+    #[serde(default)]
     pub r#type: String,
     pub param: Option<String>,
     pub code: Option<String>
 }
+
+impl OpenAIError {
+    /// Whether the account ran out of credit. OpenAI sends this as a 429 like a rate limit, but
+    /// retrying it doesn't help
+    pub fn is_quota_exceeded(&self) -> bool {
+        self.error.code.as_deref() == Some("insufficient_quota")
+    }
+}
+
+impl std::fmt::Display for OpenAIError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        let OpenAIErrorInner { message, r#type, param, code } = &self.error;
+
+        write!(f, "OpenAI responded with an error: {}", message)?;
+        match (r#type.is_empty(), code) {
+            (false, Some(code)) => write!(f, " ({}, {})", r#type, code)?,
+            (false, None) => write!(f, " ({})", r#type)?,
+            (true, Some(code)) => write!(f, " ({})", code)?,
+            (true, None) => {}
+        }
+        if let Some(param) = param {
+            write!(f, " for the {} parameter", param)?;
+        }
+        Ok(())
+    }
+}
@@ -1,8 +1,210 @@
+use std::env;
+use reqwest::{Client,Method,RequestBuilder,StatusCode};
+use serde::Deserialize;
+use crate::chat::ChatError;
+use crate::config::{self,ApiKeyError,Config};
+use crate::request;
+
 pub mod session;
 pub mod error;
 pub mod response;
 pub mod chat;
 
 pub use error::OpenAIError;
+
+/// The maximum number of stop sequences OpenAI accepts in a single request
+pub const OPENAI_STOP_SEQUENCES_MAX: usize = 4;
+
+/// The maximum number of alternatives OpenAI returns for each token's log probability
+pub const OPENAI_TOP_LOGPROBS_MAX: u8 = 20;
+
+/// The seed sent with requests at a temperature of 0 that don't set their own, so repeated
+/// requests return the same result as far as OpenAI allows
+pub const OPENAI_ZERO_TEMPERATURE_SEED: u64 = 0;
+
 pub use session::OpenAISessionCommand;
 pub use chat::OpenAIChatCommand;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum OpenAIEndpoint {
+    ChatCompletions,
+    Completions,
+    ImageGenerations,
+    Models
+}
+
+impl OpenAIEndpoint {
+    fn path(&self) -> &str {
+        match self {
+            OpenAIEndpoint::ChatCompletions => "chat/completions",
+            OpenAIEndpoint::Completions => "completions",
+            OpenAIEndpoint::ImageGenerations => "images/generations",
+            OpenAIEndpoint::Models => "models"
+        }
+    }
+}
+
+/// Whether a model is one of OpenAI's o-series reasoning models, like o1 or o1-mini
+pub(crate) fn is_reasoning_model(model: &str) -> bool {
+    let mut chars = model.chars();
+    chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+/// The body field limiting the tokens generated. Reasoning models reject max_tokens, since their
+/// hidden reasoning tokens count towards the limit too
+pub(crate) fn max_tokens_field(model: &str) -> &'static str {
+    if is_reasoning_model(model) {
+        "max_completion_tokens"
+    } else {
+        "max_tokens"
+    }
+}
+
+/// The seed to send with a request, falling back to a fixed seed at a temperature of 0
+pub(crate) fn seed(seed: Option<u64>, temperature: f32) -> Option<u64> {
+    seed.or_else(|| (temperature == 0.0).then_some(OPENAI_ZERO_TEMPERATURE_SEED))
+}
+
+/// Whether a streamed message is a heartbeat some OpenAI compatible gateways send to keep the
+/// connection open, either an empty data line or an SSE comment, rather than a chunk to parse
+pub(crate) fn is_keep_alive(data: &str) -> bool {
+    let data = data.trim();
+    data.is_empty() || data.starts_with(':')
+}
+
+/// Reads the OpenAI error out of a stream that was refused with an unsuccessful status, so an
+/// exhausted quota can be told apart from a rate limit like it is without streaming. Other stream
+/// errors, and error bodies that aren't an OpenAI error, are given back as they are
+pub(crate) async fn read_stream_error(
+    error: reqwest_eventsource::Error) -> Result<OpenAIError, reqwest_eventsource::Error>
+{
+    match error {
+        reqwest_eventsource::Error::InvalidStatusCode(status, response) => {
+            let body = response.bytes().await.unwrap_or_default();
+            serde_json::from_slice(&body).map_err(|_| {
+                let response = http::Response::builder()
+                    .status(status)
+                    .body(body)
+                    .expect("A status and a body make up a valid response");
+                reqwest_eventsource::Error::InvalidStatusCode(status, response.into())
+            })
+        },
+        error => Err(error)
+    }
+}
+
+/// Starts an authenticated POST request to an OpenAI endpoint, or to the matching Azure
+/// deployment endpoint when Azure is configured. An explicitly passed API key takes precedence
+/// over the environment (OPENAI_API_KEY, then OPEN_AI_API_KEY), then the key file and finally the
+/// key in the config. Returns None when no API key could be found, and an error when the key found
+/// is malformed. Keys for OpenAI's own API have to start with sk-, keys for Azure or a custom base
+/// URL are only trimmed.
+pub(crate) fn post(
+    client: &Client,
+    config: &Config,
+    api_key: Option<&str>,
+    endpoint: OpenAIEndpoint) -> Result<Option<RequestBuilder>, ApiKeyError>
+{
+    authorized_request(client, config, api_key, Method::POST, endpoint)
+}
+
+/// Checks the API key and the connection to OpenAI by listing the models, which doesn't generate
+/// anything. A rejected key is returned as ApiKeyRejected, and network failures as Request or
+/// Timeout errors
+pub(crate) async fn verify(
+    client: &Client,
+    config: &Config,
+    api_key: Option<&str>) -> Result<(), ChatError>
+{
+    list_models(client, config, api_key).await.map(|_| ())
+}
+
+/// The ids of the models the API key has access to, sorted by name. The API key is looked up
+/// like it is for the other requests when None is passed
+pub async fn list_models(
+    client: &Client,
+    config: &Config,
+    api_key: Option<&str>) -> Result<Vec<String>, ChatError>
+{
+    let request = authorized_request(client, config, api_key, Method::GET, OpenAIEndpoint::Models)?
+        .ok_or(ChatError::Unauthorized)?;
+    let response = request::send(request, config)
+        .await
+        .map_err(ChatError::from_request_error)?;
+
+    log::debug!("OpenAI responded to the models request with {}", response.status());
+    match response.status() {
+        status if status.is_success() => {},
+        StatusCode::UNAUTHORIZED => return Err(ChatError::ApiKeyRejected),
+        _ => return Err(ChatError::from_openai_error(response.json().await?))
+    }
+
+    let models: OpenAIModelList = response.json().await?;
+    let mut ids: Vec<String> = models.data.into_iter().map(|model| model.id).collect();
+    ids.sort();
+    Ok(ids)
+}
+
+#[derive(Deserialize)]
+struct OpenAIModelList {
+    data: Vec<OpenAIModelListEntry>
+}
+
+#[derive(Deserialize)]
+struct OpenAIModelListEntry {
+    id: String
+}
+
+fn authorized_request(
+    client: &Client,
+    config: &Config,
+    api_key: Option<&str>,
+    method: Method,
+    endpoint: OpenAIEndpoint) -> Result<Option<RequestBuilder>, ApiKeyError>
+{
+    let api_key = api_key.map(String::from);
+
+    match &config.azure {
+        Some(azure) => {
+            let key = api_key
+                .or_else(|| env::var("AZURE_OPENAI_API_KEY").ok())
+                .or_else(|| azure.api_key.clone())
+                .map(|key| config::check_api_key(&key, config.openai_api_key_prefix()))
+                .transpose()?;
+
+            // Listing the models isn't scoped to the deployment
+            let url = match endpoint {
+                OpenAIEndpoint::Models => azure.resource_url(endpoint.path()),
+                _ => azure.url(endpoint.path())
+            };
+
+            Ok(key.map(|key| {
+                let request = client.request(method, url).header("api-key", key);
+                request::prepare(request, config)
+            }))
+        },
+        None => {
+            // OPENAI_API_KEY is the name OpenAI's own tools use, OPEN_AI_API_KEY is still read
+            // for existing setups
+            let key = api_key
+                .or_else(|| env::var("OPENAI_API_KEY").ok())
+                .or_else(|| env::var("OPEN_AI_API_KEY").ok());
+            let key = match key {
+                Some(key) => Some(key),
+                None => config.openai_api_key()?
+            };
+            let prefix = config.openai_api_key_prefix();
+            let key = key.map(|key| config::check_api_key(&key, prefix)).transpose()?;
+            let url = config.openai_url(&config.openai_api_path(endpoint.path()));
+
+            Ok(key.map(|key| {
+                let request = client.request(method, url).bearer_auth(key);
+                let request = match &config.organization {
+                    Some(organization) => request.header("OpenAI-Organization", organization),
+                    None => request
+                };
+                request::prepare(request, config)
+            }))
+        }
+    }
+}
@@ -1,22 +1,45 @@
-use serde::Deserialize;
+use serde::{Serialize,Deserialize};
 
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize)]
 pub struct OpenAICompletionResponse<T> {
     pub choices: Vec<T>,
     pub created: usize,
     pub model: String,
     pub object: String,
     pub id: String,
-    pub usage: Option<OpenAIUsage>
+    pub usage: Option<OpenAIUsage>,
+    /// Identifies the backend configuration that served the request, if this changes between
+    /// requests with the same seed the results may differ
+    pub system_fingerprint: Option<String>
 }
 
-#[derive(Deserialize)]
+impl<T> OpenAICompletionResponse<T> {
+    /// The response id and the model that generated it, for logging
+    pub fn info(&self) -> String {
+        format!("response: {} from the {} model", self.id, self.model)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct OpenAIUsage {
     pub prompt_tokens: usize,
     pub completion_tokens: usize,
     pub total_tokens: usize
 }
 
+impl OpenAIUsage {
+    pub(crate) fn cost_summary(&self, model: &str) -> String {
+        crate::pricing::cost_summary(model, self.prompt_tokens, self.completion_tokens)
+    }
+}
+
+impl std::fmt::Display for OpenAIUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "usage: {} prompt tokens, {} completion tokens, {} total tokens",
+            self.prompt_tokens, self.completion_tokens, self.total_tokens)
+    }
+}
+
 #[derive(Deserialize)]
 pub struct OpenAIChoice {
     pub text: String,
@@ -1,60 +1,408 @@
 use serde_json::json;
 use serde::Deserialize;
-use crate::session::{SessionResult,SessionOptions,SessionError,ModelFocus,Model};
-use crate::{Config};
-use reqwest::Client;
+use crate::session::{SessionCommand,SessionResult,SessionOptions,SessionError,ModelFocus,Model};
+use crate::{cache,request,Config};
+use reqwest::{Client,RequestBuilder};
+use reqwest_eventsource::{EventSource,Event};
+use futures_util::stream::{self,StreamExt};
+use crate::completion::ClashingArgumentsError;
+use crate::chat::parse_stream_chunk;
+use super::chat::{OpenAIChatChoice,OpenAIChatDelta,OpenAIChatModel};
 use super::response::OpenAICompletionResponse;
-use std::env;
+use crate::openai::{self,OpenAIEndpoint,OPENAI_STOP_SEQUENCES_MAX};
+use std::collections::HashMap;
+use std::io::{self,Write};
 
 #[derive(Debug, Default)]
 pub struct OpenAISessionCommand {
+    api_key: Option<String>,
+    best_of: Option<usize>,
+    chat_model: Option<OpenAIChatModel>,
+    dry_run: bool,
+    echo: bool,
+    max_tokens: usize,
     temperature: OpenAITemperature,
+    top_p: Option<f32>,
+    stop: Option<Vec<String>>,
+    seed: Option<u64>,
+    logit_bias: HashMap<u32, f32>,
     model: OpenAIModel,
-    response_count: usize
+    prefix_ai: Option<String>,
+    quiet: bool,
+    response_count: usize,
+    show_usage: bool,
+    show_cost: bool,
+    show_response_info: bool,
+    stream: bool,
+    suffix: Option<String>,
+    user: Option<String>
 }
 
 impl TryFrom<&SessionOptions> for OpenAISessionCommand {
     type Error = SessionError;
 
     fn try_from(options: &SessionOptions) -> Result<Self, SessionError> {
+        options.completion.validate_response_count()?;
+        let response_count = options.completion.response_count.unwrap_or(1);
+        if let Some(best_of) = options.best_of {
+            if best_of < response_count {
+                return Err(SessionError::InvalidBestOf);
+            }
+        }
+        let best_of = options.best_of.filter(|best_of| *best_of > 1);
+
+        if let Some(stop) = &options.completion.stop {
+            if stop.len() > OPENAI_STOP_SEQUENCES_MAX {
+                return Err(SessionError::TooManyStopSequences);
+            }
+        }
+
+        let completions_only = options.echo || best_of.is_some() || options.suffix.is_some();
+        if options.chat_model.is_some() && completions_only {
+            return Err(SessionError::ClashingArguments(ClashingArgumentsError::new(
+                "Echo, best of and suffix aren't supported by the chat completions endpoint")));
+        }
+
         Ok(Self {
+            api_key: options.completion.api_key.clone(),
+            best_of,
+            chat_model: options.chat_model,
+            dry_run: options.completion.dry_run.unwrap_or(false),
+            echo: options.echo,
+            max_tokens: options.max_tokens.unwrap_or(1000),
             model: OpenAIModel::try_from((options.model_focus, options.model))?,
             temperature:
                 OpenAITemperature::try_from(options.completion.temperature.unwrap_or(0.8))?,
-            response_count: options.completion.response_count.unwrap_or(1),
+            top_p: options.completion.top_p,
+            stop: options.completion.stop.clone(),
+            seed: options.completion.seed,
+            logit_bias: options.completion.logit_bias.iter().flatten().copied().collect(),
+            prefix_ai: options.completion.prefix_ai.clone(),
+            quiet: options.completion.quiet.unwrap_or(false),
+            response_count,
+            show_usage: options.completion.show_usage.unwrap_or(false),
+            show_cost: options.completion.show_cost.unwrap_or(false),
+            show_response_info: options.completion.show_response_info.unwrap_or(false),
+            // Multiple responses would interleave with each other when printed as they arrive, and
+            // OpenAI can't stream a response picked from several
+            stream: options.stream && response_count == 1 && best_of.is_none(),
+            suffix: options.suffix.clone(),
+            user: options.completion.user.clone(),
         })
     }
 }
 
+impl TryFrom<(&SessionCommand, &Config)> for OpenAISessionCommand {
+    type Error = SessionError;
+
+    fn try_from(args: (&SessionCommand, &Config)) -> Result<Self, SessionError> {
+        OpenAISessionCommand::try_from(&SessionOptions::try_from(args)?)
+    }
+}
+
 impl OpenAISessionCommand {
+    pub fn max_tokens(&self) -> usize {
+        self.max_tokens
+    }
+
+    pub fn model(&self) -> OpenAIModel {
+        self.model
+    }
+
+    /// The chat model the prompt is sent to as a user message, None when it's sent to the
+    /// completions endpoint
+    pub fn chat_model(&self) -> Option<OpenAIChatModel> {
+        self.chat_model
+    }
+
+    /// The versioned name of the model the request is sent to
+    fn versioned_model(&self) -> &str {
+        match &self.chat_model {
+            Some(chat_model) => chat_model.to_versioned(),
+            None => self.model.to_versioned()
+        }
+    }
+
+    pub fn response_count(&self) -> usize {
+        self.response_count
+    }
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature.0
+    }
+
+    pub fn top_p(&self) -> Option<f32> {
+        self.top_p
+    }
+
+    /// Whether the completion is printed to stdout while it's being generated
+    pub fn streams(&self) -> bool {
+        self.stream
+    }
+
     pub async fn run(&self,
         client: &Client,
         config: &Config,
         prompt: &str) -> SessionResult
     {
-        let request = client.post("https://api.openai.com/v1/completions")
-            .bearer_auth(env::var("OPEN_AI_API_KEY")
-                .ok()
-                .or_else(|| config.api_key_openai.clone())
-                .ok_or_else(|| SessionError::Unauthorized)?
-            )
-            .json(&json!({
-                "model": self.model.to_versioned(),
-                "prompt": &prompt,
-                "max_tokens": 1000,
-                "temperature": self.temperature.0,
-                "n": self.response_count
-            }))
-            .send()
+        self.complete(client, config, prompt, self.stream).await
+    }
+
+    /// Runs the prompts concurrently, with at most max_concurrent_requests from the config in
+    /// flight at once. The results are in the same order as the prompts. The completions aren't
+    /// streamed, since they'd interleave with each other when printed
+    pub async fn run_batch(&self,
+        client: &Client,
+        config: &Config,
+        prompts: &[String]) -> Vec<SessionResult>
+    {
+        stream::iter(prompts)
+            .map(|prompt| self.complete(client, config, prompt, false))
+            .buffered(config.max_concurrent_requests())
+            .collect()
             .await
-            .expect("Failed to send completion");
+    }
+
+    async fn complete(&self,
+        client: &Client,
+        config: &Config,
+        prompt: &str,
+        stream: bool) -> SessionResult
+    {
+        let mut body = json!({
+            "model": self.versioned_model(),
+            "n": self.response_count,
+            "stream": stream
+        });
+        body[openai::max_tokens_field(self.versioned_model())] = json!(self.max_tokens);
+
+        if stream && (self.show_usage || self.show_cost) {
+            body["stream_options"] = json!({ "include_usage": true });
+        }
+
+        let endpoint = match self.chat_model {
+            Some(_) => {
+                body["messages"] = json!([{ "role": "user", "content": &prompt }]);
+                OpenAIEndpoint::ChatCompletions
+            },
+            None => {
+                body["prompt"] = json!(&prompt);
+                OpenAIEndpoint::Completions
+            }
+        };
+
+        // Reasoning models reject any sampling options other than their defaults
+        if !openai::is_reasoning_model(self.versioned_model()) {
+            body["temperature"] = json!(self.temperature.0);
+
+            if let Some(top_p) = self.top_p {
+                body["top_p"] = json!(top_p);
+            }
+        }
+
+        if let Some(stop) = &self.stop {
+            body["stop"] = json!(stop);
+        }
+
+        if let Some(seed) = openai::seed(self.seed, self.temperature.0) {
+            body["seed"] = json!(seed);
+        }
+
+        if !self.logit_bias.is_empty() {
+            body["logit_bias"] = json!(self.logit_bias);
+        }
 
-        if !request.status().is_success() {
-            return Err(SessionError::OpenAIError(request.json().await?));
+        if self.echo {
+            body["echo"] = json!(true);
         }
 
-        let session_response: OpenAICompletionResponse<OpenAISessionChoice> = request.json().await?;
-        Ok(session_response.choices.into_iter().map(|r| r.text).collect())
+        if let Some(best_of) = self.best_of {
+            body["best_of"] = json!(best_of);
+        }
+
+        if let Some(suffix) = &self.suffix {
+            body["suffix"] = json!(suffix);
+        }
+
+        if let Some(user) = self.user.as_ref().or(config.user.as_ref()) {
+            body["user"] = json!(user);
+        }
+
+        if self.dry_run {
+            println!("{}", serde_json::to_string_pretty(&body)?);
+            return Ok(vec![]);
+        }
+
+        log::debug!("Requesting an OpenAI completion from {}", self.versioned_model());
+        log::trace!("Request body: {}", body);
+
+        let request = openai::post(client, config, self.api_key.as_deref(), endpoint)?
+            .ok_or(SessionError::Unauthorized)?
+            .json(&body);
+
+        let request = match config.timeout() {
+            Some(timeout) => request.timeout(timeout),
+            None => request
+        };
+
+        if stream {
+            request::throttle(&request, config).await;
+            return self.run_stream(request).await;
+        }
+
+        let response = match cache::get(config, &request, &body) {
+            Some(response) => response,
+            None => {
+                let cache_request = request.try_clone();
+                let request = request::send(request, config)
+                    .await
+                    .map_err(SessionError::from_request_error)?;
+
+                log::debug!("OpenAI responded with {}", request.status());
+                if !request.status().is_success() {
+                    return Err(SessionError::from_openai_error(request.json().await?));
+                }
+
+                let response = request.text().await.map_err(SessionError::from_request_error)?;
+                if let Some(cache_request) = cache_request {
+                    cache::put(config, &cache_request, &body, &response);
+                }
+                response
+            }
+        };
+
+        match self.chat_model {
+            Some(_) => {
+                let chat_response: OpenAICompletionResponse<OpenAIChatChoice> =
+                    serde_json::from_str(&response)?;
+                self.print_response_details(&chat_response);
+
+                Ok(chat_response.choices
+                    .into_iter()
+                    .map(|choice| choice.message.map(|m| m.content.text()).unwrap_or_default())
+                    .collect())
+            },
+            None => {
+                let session_response: OpenAICompletionResponse<OpenAISessionChoice> =
+                    serde_json::from_str(&response)?;
+                self.print_response_details(&session_response);
+
+                Ok(session_response.choices.into_iter().map(|r| r.text).collect())
+            }
+        }
+    }
+
+    fn print_response_details<T>(&self, response: &OpenAICompletionResponse<T>) {
+        self.print_usage(response);
+        if self.show_response_info {
+            eprintln!("{}", response.info());
+        }
+    }
+
+    fn print_usage<T>(&self, response: &OpenAICompletionResponse<T>) {
+        if let (true, Some(usage)) = (self.show_usage, &response.usage) {
+            eprintln!("{}", usage);
+        }
+        if let (true, Some(usage)) = (self.show_cost, &response.usage) {
+            eprintln!("{}", usage.cost_summary(&response.model));
+        }
+    }
+
+    /// The response info and the text of the first choice in a streamed chunk, from either
+    /// endpoint, None for a chunk that isn't JSON. The usage is printed when the chunk has it,
+    /// which is only the last one
+    fn read_chunk(&self, data: &str) -> Result<Option<(String, Option<String>)>, SessionError> {
+        let Some(chunk) = parse_stream_chunk(data, "OpenAI completion") else {
+            return Ok(None);
+        };
+        if chunk.get("error").is_some() {
+            return Err(SessionError::from_openai_error(serde_json::from_value(chunk)?));
+        }
+
+        Ok(Some(match self.chat_model {
+            Some(_) => {
+                let chunk: OpenAICompletionResponse<OpenAIChatDelta> = serde_json::from_value(chunk)?;
+                self.print_usage(&chunk);
+                (chunk.info(), chunk.choices.into_iter().next().and_then(|c| c.delta.content))
+            },
+            None => {
+                let chunk: OpenAICompletionResponse<OpenAISessionChoice> =
+                    serde_json::from_value(chunk)?;
+                self.print_usage(&chunk);
+                (chunk.info(), chunk.choices.into_iter().next().map(|c| c.text))
+            }
+        }))
+    }
+
+    async fn run_stream(&self, request: RequestBuilder) -> SessionResult {
+        let mut stream = EventSource::new(request)?;
+        let mut response = String::new();
+
+        match &self.prefix_ai {
+            Some(prefix) if !self.quiet => write!(io::stdout(), "{}", prefix)?,
+            _ => {}
+        }
+
+        'stream: while let Some(event) = stream.next().await {
+            match event {
+                Ok(Event::Open) => log::debug!("Opened the OpenAI completion stream"),
+                Ok(Event::Message(message)) if message.data.trim() == "[DONE]" => {
+                    log::debug!("The OpenAI completion stream is done");
+                    break 'stream;
+                },
+                Ok(Event::Message(message)) if openai::is_keep_alive(&message.data) => {
+                    log::trace!("Skipping a keep alive message in the OpenAI completion stream");
+                },
+                Ok(Event::Message(message)) => {
+                    log::trace!("Stream message: {}", message.data);
+                    let (info, text) = match self.read_chunk(&message.data) {
+                        Ok(Some(chunk)) => chunk,
+                        Ok(None) => continue 'stream,
+                        Err(error) => {
+                            stream.close();
+                            return Err(error);
+                        }
+                    };
+
+                    if self.show_response_info && response.is_empty() {
+                        eprintln!("{}", info);
+                    }
+
+                    if let Some(text) = text {
+                        let text = if response.is_empty() {
+                            text.trim_start()
+                        } else {
+                            &text
+                        };
+
+                        if !self.quiet {
+                            let mut stdout = io::stdout();
+                            write!(stdout, "{}", text)?;
+                            stdout.flush()?;
+                        }
+                        response.push_str(text);
+                    }
+                },
+                // Some OpenAI compatible servers close the stream without sending [DONE]
+                Err(reqwest_eventsource::Error::StreamEnded) => break 'stream,
+                Err(reqwest_eventsource::Error::Transport(err)) if err.is_timeout() => {
+                    stream.close();
+                    return Err(SessionError::Timeout);
+                },
+                Err(err) => {
+                    stream.close();
+                    return Err(match openai::read_stream_error(err).await {
+                        Ok(error) => SessionError::from_openai_error(error),
+                        Err(err) => SessionError::EventSource(Box::new(err))
+                    });
+                }
+            }
+        }
+
+        if !self.quiet {
+            writeln!(io::stdout())?;
+        }
+        Ok(vec![ response ])
     }
 }
 
@@ -65,9 +413,10 @@ impl TryFrom<f32> for OpenAITemperature {
     type Error = SessionError;
 
     fn try_from(n: f32) -> Result<Self, SessionError> {
-        match n.floor() as u32 {
-            0..=2 => Ok(OpenAITemperature(n)),
-            _ => Err(SessionError::TemperatureOutOfValidRange)
+        if n.is_finite() && (0.0..=2.0).contains(&n) {
+            Ok(OpenAITemperature(n))
+        } else {
+            Err(SessionError::TemperatureOutOfValidRange)
         }
     }
 }
@@ -75,6 +424,9 @@ impl TryFrom<f32> for OpenAITemperature {
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub enum OpenAIModel {
     #[default]
+    Gpt35TurboInstruct,
+    Davinci002,
+    Babbage002,
     TextDavinci,
     TextCurie,
     TextBabbage,
@@ -86,6 +438,9 @@ pub enum OpenAIModel {
 impl OpenAIModel {
     pub fn to_versioned(&self) -> &str {
         match self {
+            OpenAIModel::Gpt35TurboInstruct => "gpt-3.5-turbo-instruct",
+            OpenAIModel::Davinci002 => "davinci-002",
+            OpenAIModel::Babbage002 => "babbage-002",
             OpenAIModel::TextDavinci => "text-davinci-003",
             OpenAIModel::TextCurie => "text-curie-001",
             OpenAIModel::TextBabbage => "text-babbage-001",
@@ -110,33 +465,44 @@ impl TryFrom<(ModelFocus, Model)> for OpenAIModel {
     type Error = SessionError;
 
     fn try_from(models: (ModelFocus, Model)) -> Result<OpenAIModel, SessionError> {
+        // OpenAI retired the ada, curie and codex models, so these map onto the remaining
+        // completion models. The older variants can still be used against compatible servers.
         Ok(match models {
-            (ModelFocus::Text, Model::Tiny) => OpenAIModel::TextAda,
             (ModelFocus::Code, Model::Tiny) |
             (ModelFocus::Code, Model::Small) => {
                 return Err(SessionError::NoMatchingModel)
             },
-            (ModelFocus::Text, Model::Small) => OpenAIModel::TextBabbage,
-            (ModelFocus::Text, Model::Medium) => OpenAIModel::TextCurie,
-            (ModelFocus::Code, Model::Medium) => OpenAIModel::CodeCushman,
+            (ModelFocus::Text, Model::Tiny) => {
+                warn_inexact_match!("tiny", "text");
+                OpenAIModel::Babbage002
+            },
+            (ModelFocus::Text, Model::Small) => OpenAIModel::Babbage002,
+            (ModelFocus::Text, Model::Medium) => {
+                warn_inexact_match!("medium", "text");
+                OpenAIModel::Davinci002
+            },
+            (ModelFocus::Code, Model::Medium) => {
+                warn_inexact_match!("medium", "code");
+                OpenAIModel::Gpt35TurboInstruct
+            },
             (ModelFocus::Text, Model::Large) => {
                 warn_inexact_match!("large", "text");
-                OpenAIModel::TextCurie
+                OpenAIModel::Davinci002
             },
             (ModelFocus::Code, Model::Large) => {
                 warn_inexact_match!("large", "code");
-                OpenAIModel::CodeCushman
+                OpenAIModel::Gpt35TurboInstruct
             },
             (ModelFocus::Text, Model::XLarge) => {
                 warn_inexact_match!("x-large", "text");
-                OpenAIModel::TextCurie
+                OpenAIModel::Davinci002
             },
             (ModelFocus::Code, Model::XLarge) => {
                 warn_inexact_match!("x-large", "code");
-                OpenAIModel::CodeCushman
+                OpenAIModel::Gpt35TurboInstruct
             },
-            (ModelFocus::Code, Model::XXLarge) => OpenAIModel::CodeDavinci,
-            (ModelFocus::Text, Model::XXLarge) => OpenAIModel::TextDavinci,
+            (ModelFocus::Code, Model::XXLarge) => OpenAIModel::Gpt35TurboInstruct,
+            (ModelFocus::Text, Model::XXLarge) => OpenAIModel::Gpt35TurboInstruct,
         })
     }
 }
@@ -148,3 +514,17 @@ pub struct OpenAISessionChoice {
     pub logprobs: Option<u32>,
     pub finish_reason: Option<String>
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temperatures_outside_the_valid_range_are_rejected() {
+        assert!(OpenAITemperature::try_from(0.0).is_ok());
+        assert!(OpenAITemperature::try_from(2.0).is_ok());
+        for temperature in [-1.0, -0.5, 2.5, f32::NAN, f32::INFINITY] {
+            assert!(OpenAITemperature::try_from(temperature).is_err(), "{}", temperature);
+        }
+    }
+}
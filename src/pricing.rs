@@ -0,0 +1,64 @@
+/// Dollars per million prompt and completion tokens by model name. Versioned model names like
+/// gpt-4o-2024-05-13 are priced by the longest name they start with.
+pub const MODEL_PRICES: &[(&str, f64, f64)] = &[
+    ("gpt-4o", 2.5, 10.0),
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-4", 30.0, 60.0),
+    ("o1-mini", 3.0, 12.0),
+    ("o1", 15.0, 60.0),
+    ("gpt-3.5-turbo-instruct", 1.5, 2.0),
+    ("gpt-3.5-turbo", 0.5, 1.5),
+    ("davinci-002", 2.0, 2.0),
+    ("babbage-002", 0.4, 0.4),
+    ("claude-3-opus", 15.0, 75.0),
+    ("claude-3-5-sonnet", 3.0, 15.0),
+    ("claude-3-haiku", 0.25, 1.25),
+];
+
+/// Estimates the cost of a request in dollars from the tokens the provider reported. Returns None
+/// for models without a known price.
+pub fn estimate_cost(model: &str, prompt_tokens: usize, completion_tokens: usize) -> Option<f64> {
+    let (_, prompt_price, completion_price) = MODEL_PRICES
+        .iter()
+        .filter(|(name, _, _)| model.starts_with(name))
+        .max_by_key(|(name, _, _)| name.len())?;
+
+    Some((prompt_tokens as f64 * prompt_price + completion_tokens as f64 * completion_price)
+        / 1_000_000.0)
+}
+
+/// The estimated cost line printed after a completion
+pub(crate) fn cost_summary(model: &str, prompt_tokens: usize, completion_tokens: usize) -> String {
+    match estimate_cost(model, prompt_tokens, completion_tokens) {
+        Some(cost) => format!("cost: ${:.6} (estimated)", cost),
+        None => format!("cost: unknown, there's no price for the {} model", model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn costs_are_estimated_per_million_tokens() {
+        assert_eq!(Some(30.0 + 60.0), estimate_cost("gpt-4", 1_000_000, 1_000_000));
+        assert_eq!(Some(0.0025), estimate_cost("gpt-4o", 1_000, 0));
+        assert_eq!(Some(0.0), estimate_cost("gpt-4o", 0, 0));
+    }
+
+    #[test]
+    fn versioned_models_are_priced_by_the_longest_matching_name() {
+        assert_eq!(Some(10.0), estimate_cost("gpt-4o-2024-05-13", 0, 1_000_000));
+        assert_eq!(Some(30.0), estimate_cost("gpt-4-turbo-2024-04-09", 0, 1_000_000));
+        assert_eq!(Some(12.0), estimate_cost("o1-mini-2024-09-12", 0, 1_000_000));
+    }
+
+    #[test]
+    fn unknown_models_have_no_cost() {
+        assert_eq!(None, estimate_cost("llama-3", 1_000, 1_000));
+        assert_eq!(
+            "cost: unknown, there's no price for the llama-3 model",
+            cost_summary("llama-3", 1, 1));
+        assert_eq!("cost: $0.000090 (estimated)", cost_summary("gpt-4", 1, 1));
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::{Duration,Instant};
+use reqwest::{RequestBuilder,Response,StatusCode};
+use reqwest::header::RETRY_AFTER;
+use crate::chat::count_tokens;
+use crate::openai::OpenAIError;
+use crate::Config;
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// The requests sent in the last minute with their estimated tokens. This is shared by the whole
+/// process, since that's what the provider's account quotas apply to.
+static RATE_LIMIT_WINDOW_REQUESTS: Mutex<VecDeque<(Instant, usize)>> = Mutex::new(VecDeque::new());
+
+/// Instrumentation for the requests sent to AI providers, for tracing, metrics or extra headers.
+/// Add it to the middleware in the config to have it run for every request the commands send.
+pub trait RequestMiddleware: Debug + Send + Sync {
+    /// Adjusts a request before it's sent. Streamed requests and retries go through this too
+    fn on_request(&self, request: RequestBuilder) -> RequestBuilder {
+        request
+    }
+
+    /// Sees each response before it's parsed, including responses that are retried. Streamed
+    /// responses aren't passed through this
+    fn on_response(&self, _response: &Response) {}
+}
+
+/// Counts of how the requests sent to AI providers went, for monitoring a long running process.
+/// The counters only go up, read them periodically and compare. Streamed requests aren't counted
+#[derive(Debug, Default)]
+pub struct RequestStats {
+    retries: AtomicUsize,
+    failures: AtomicUsize,
+    successes: AtomicUsize
+}
+
+impl RequestStats {
+    /// The number of times a rate limited or failed request was sent again
+    pub fn retries(&self) -> usize {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests that failed to send or got an error response after any retries
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// The number of requests that got a successful response
+    pub fn successes(&self) -> usize {
+        self.successes.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, response: &reqwest::Result<Response>) {
+        let counter = match response {
+            Ok(response) if response.status().is_success() => &self.successes,
+            _ => &self.failures
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs a newly built request through the middleware in the config
+pub(crate) fn prepare(request: RequestBuilder, config: &Config) -> RequestBuilder {
+    config.middleware
+        .iter()
+        .fold(request, |request, middleware| middleware.on_request(request))
+}
+
+pub(crate) fn observe(response: &Response, config: &Config) {
+    for middleware in &config.middleware {
+        middleware.on_response(response);
+    }
+}
+
+/// Sends a request to an AI provider, retrying rate limited (429) and server error (5xx)
+/// responses with an exponential backoff. The `Retry-After` header is used as the delay when the
+/// provider sends one. The last response is returned as is once the retries run out, so callers
+/// still get to parse the providers error body. A 429 for an exhausted OpenAI quota is returned
+/// right away, since waiting doesn't give the account more credit.
+pub(crate) async fn send(request: RequestBuilder, config: &Config) -> reqwest::Result<Response> {
+    // Retries are part of the same logical request, so they don't count against the rate limits
+    // again
+    throttle(&request, config).await;
+
+    let mut attempt = 0;
+    loop {
+        let retry = match request.try_clone() {
+            Some(retry) if attempt < config.max_retries() => retry,
+            _ => {
+                let response = request.send().await;
+                config.stats.record(&response);
+                let response = response?;
+                observe(&response, config);
+                return Ok(response);
+            }
+        };
+
+        let (response, quota_exceeded) = match retry.send().await {
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                match read_quota_exceeded(response).await {
+                    Ok((response, quota_exceeded)) => (Ok(response), quota_exceeded),
+                    Err(error) => (Err(error), false)
+                }
+            },
+            response => (response, false)
+        };
+        let retrying = !quota_exceeded
+            && matches!(&response, Ok(response) if should_retry(response.status()));
+        if !retrying {
+            config.stats.record(&response);
+        }
+        let response = response?;
+        observe(&response, config);
+        log::debug!("Received a {} response", response.status());
+        if !retrying {
+            return Ok(response);
+        }
+
+        let delay = retry_delay(&response, attempt, config);
+        log::debug!("Retrying the request in {:?}, attempt {} of {}",
+            delay, attempt + 1, config.max_retries());
+        tokio::time::sleep(delay).await;
+        config.stats.retries.fetch_add(1, Ordering::Relaxed);
+        attempt += 1;
+    }
+}
+
+/// Waits until sending the request stays within the requests_per_minute and tokens_per_minute
+/// limits in the config. The tokens of a request are estimated from its body, the tokens of the
+/// response aren't known up front and aren't counted.
+pub(crate) async fn throttle(request: &RequestBuilder, config: &Config) {
+    if config.requests_per_minute.is_none() && config.tokens_per_minute.is_none() {
+        return;
+    }
+
+    let tokens = request.try_clone()
+        .and_then(|request| request.build().ok())
+        .and_then(|request| {
+            let body = request.body()?.as_bytes()?;
+            Some(count_tokens(&String::from_utf8_lossy(body)))
+        })
+        .unwrap_or(0);
+
+    loop {
+        let wait = {
+            let mut window = RATE_LIMIT_WINDOW_REQUESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            while let Some((sent, _)) = window.front() {
+                if now.duration_since(*sent) < RATE_LIMIT_WINDOW {
+                    break;
+                }
+                window.pop_front();
+            }
+
+            let requests_fit = config.requests_per_minute.is_none_or(|max| window.len() < max);
+            let window_tokens: usize = window.iter().map(|(_, tokens)| tokens).sum();
+            // A request over the token limit on its own is let through once the window is empty
+            let tokens_fit = config.tokens_per_minute
+                .is_none_or(|max| window.is_empty() || window_tokens + tokens <= max);
+
+            if requests_fit && tokens_fit {
+                log::trace!("Sending a request estimated at {} tokens, {} requests and {} tokens \
+                    were sent in the last minute", tokens, window.len(), window_tokens);
+                window.push_back((now, tokens));
+                return;
+            }
+
+            match window.front() {
+                Some((sent, _)) => RATE_LIMIT_WINDOW.saturating_sub(now.duration_since(*sent)),
+                None => return
+            }
+        };
+
+        log::debug!("Waiting {:?} to stay within the rate limits", wait);
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Reads the body of a rate limited response to tell an exhausted quota apart from a rate limit.
+/// The body is put back into the returned response, so callers can still parse it
+async fn read_quota_exceeded(response: Response) -> reqwest::Result<(Response, bool)> {
+    let mut builder = http::Response::builder()
+        .status(response.status())
+        .version(response.version());
+    if let Some(headers) = builder.headers_mut() {
+        *headers = response.headers().clone();
+    }
+
+    let body = response.bytes().await?;
+    let quota_exceeded = matches!(
+        serde_json::from_slice::<OpenAIError>(&body),
+        Ok(error) if error.is_quota_exceeded());
+    let response = builder
+        .body(body)
+        .expect("The parts of an existing response make up a valid response");
+
+    Ok((Response::from(response), quota_exceeded))
+}
+
+fn should_retry(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn retry_delay(response: &Response, attempt: usize, config: &Config) -> Duration {
+    let max_delay = config.retry_max_delay();
+    let retry_after = response.headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs);
+
+    let delay = retry_after.unwrap_or_else(|| {
+        let backoff = Duration::from_secs(1)
+            .checked_mul(2u32.saturating_pow(attempt as u32))
+            .unwrap_or(max_delay)
+            .min(max_delay);
+
+        backoff.mul_f64(1.0 - config.retry_jitter() * random_fraction())
+    });
+
+    delay.min(max_delay)
+}
+
+/// A random number from 0 up to 1. Falls back to 0, so no jitter, when the OS has no randomness
+/// to give
+fn random_fraction() -> f64 {
+    let mut bytes = [0; 8];
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64,
+        Err(_) => 0.0
+    }
+}
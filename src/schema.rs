@@ -0,0 +1,88 @@
+use serde_json::Value;
+
+/// Checks a value against the subset of JSON schema that OpenAI's structured outputs support:
+/// type, properties, required, additionalProperties, items, enum, const and anyOf. Other keywords
+/// are ignored. Returns a description of the first mismatch found.
+pub(crate) fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    validate_at(schema, value, "$")
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let Some(schema) = schema.as_object() else {
+        return Ok(());
+    };
+
+    if let Some(any_of) = schema.get("anyOf").and_then(Value::as_array) {
+        if !any_of.iter().any(|schema| validate_at(schema, value, path).is_ok()) {
+            return Err(format!("{} doesn't match any of the allowed schemas", path));
+        }
+    }
+
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(format!("{} should be {}", path, expected));
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            return Err(format!("{} should be one of {}", path, Value::Array(allowed.clone())));
+        }
+    }
+
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(r#type) => vec![r#type],
+            Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+            _ => vec![]
+        };
+
+        if !types.is_empty() && !types.iter().any(|r#type| is_type(value, r#type)) {
+            return Err(format!("{} should be of type {}", path, types.join(" or ")));
+        }
+    }
+
+    if let Value::Object(object) = value {
+        let properties = schema.get("properties").and_then(Value::as_object);
+
+        for required in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+            if let Some(required) = required.as_str() {
+                if !object.contains_key(required) {
+                    return Err(format!("{} is missing the {} property", path, required));
+                }
+            }
+        }
+
+        for (key, property) in object {
+            let property_path = format!("{}.{}", path, key);
+            match properties.and_then(|properties| properties.get(key)) {
+                Some(property_schema) => validate_at(property_schema, property, &property_path)?,
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    return Err(format!("{} isn't an allowed property", property_path));
+                },
+                None => {}
+            }
+        }
+    }
+
+    if let (Value::Array(items), Some(item_schema)) = (value, schema.get("items")) {
+        for (index, item) in items.iter().enumerate() {
+            validate_at(item_schema, item, &format!("{}[{}]", path, index))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn is_type(value: &Value, r#type: &str) -> bool {
+    match r#type {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true
+    }
+}
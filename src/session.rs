@@ -4,9 +4,10 @@ use reqwest::Client;
 use derive_more::From;
 use serde::{Serialize,Deserialize};
 use crate::openai::{OpenAISessionCommand,OpenAIError};
+use crate::openai::chat::OpenAIChatModel;
 use crate::cohere::session::{CohereSessionCommand,CohereError};
 use crate::completion::{CompletionFile,CompletionOptions,ClashingArgumentsError};
-use crate::Config;
+use crate::config::{ApiKeyError,ApiKeyFileError,Config,InvalidApiKeyError};
 
 #[derive(Args, Clone, Default, Debug, Serialize, Deserialize)]
 pub struct SessionCommand {
@@ -14,10 +15,32 @@ pub struct SessionCommand {
     #[serde(flatten)]
     pub completion: CompletionOptions,
 
+    /// The maximum number of tokens to generate in the completion. Defaults to 1000 for OpenAI
+    /// and 100 for Cohere
+    #[arg(long)]
+    pub max_tokens: Option<usize>,
+
+    /// Generate this many completions on the server and return the best ones, the ones with the
+    /// highest log probability per token. Has to be at least the response count, and the
+    /// completion isn't streamed when this is more than 1
+    #[arg(long)]
+    pub best_of: Option<usize>,
+
+    /// Include the prompt at the start of the response, as the OpenAI completions API's echo
+    /// option does
+    #[arg(long)]
+    pub echo: Option<bool>,
+
     /// Model size
     #[arg(value_enum, long, short)]
     pub model: Option<Model>,
 
+    /// Send the prompt to OpenAI's chat completions endpoint as a single user message to this chat
+    /// model, instead of to the legacy completions endpoint. Echo, best of and suffix only work
+    /// with the completions endpoint
+    #[arg(value_enum, long)]
+    pub chat_model: Option<OpenAIChatModel>,
+
     /// Model focus
     #[arg(value_enum, long)]
     pub model_focus: Option<ModelFocus>,
@@ -33,19 +56,29 @@ pub struct SessionCommand {
     /// Provider
     #[arg(long)]
     pub provider: Option<Provider>,
+
+    /// The text that comes after the completion, for filling in the middle of code or text. The
+    /// completion is generated to fit between the prompt and the suffix
+    #[arg(long)]
+    pub suffix: Option<String>,
 }
 
 #[derive(Debug, Default)]
 pub(crate) struct SessionOptions {
     pub ai_responds_first: bool,
+    pub best_of: Option<usize>,
+    pub chat_model: Option<OpenAIChatModel>,
     pub completion: CompletionOptions,
+    pub echo: bool,
     pub file: CompletionFile<SessionCommand>,
+    pub max_tokens: Option<usize>,
     pub model: Model,
     pub model_focus: ModelFocus,
     pub prompt: String,
     pub stream: bool,
     pub no_context: bool,
     pub provider: Provider,
+    pub suffix: Option<String>,
 }
 
 impl TryFrom<(&SessionCommand, &Config)> for SessionOptions {
@@ -53,7 +86,7 @@ impl TryFrom<(&SessionCommand, &Config)> for SessionOptions {
 
     fn try_from((command, config): (&SessionCommand, &Config)) -> Result<Self, Self::Error> {
         let file = command.completion.load_session_file::<SessionCommand>(config, command.clone());
-        let completion = if file.file.is_some() {
+        let completion = if file.path.is_some() {
             command.completion.merge(&file.overrides.completion)
         } else {
             command.completion.clone()
@@ -61,14 +94,24 @@ impl TryFrom<(&SessionCommand, &Config)> for SessionOptions {
 
         completion.validate()?;
 
+        let max_tokens = command.max_tokens.or(file.overrides.max_tokens);
+        if let Some(0) = max_tokens {
+            return Err(SessionError::MaxTokensOutOfValidRange);
+        }
+
         Ok(SessionOptions {
             ai_responds_first: completion.ai_responds_first.unwrap_or(false),
             stream: completion.parse_stream_option()?,
+            best_of: command.best_of.or(file.overrides.best_of),
+            chat_model: command.chat_model.or(file.overrides.chat_model),
+            echo: command.echo.or(file.overrides.echo).unwrap_or(false),
             prompt: command.parse_prompt_option(),
+            max_tokens,
             no_context: command.parse_no_context_option(),
             model: command.model.unwrap_or(Model::XXLarge),
             model_focus: command.model_focus.unwrap_or(ModelFocus::Text),
             provider: command.provider.unwrap_or(Provider::OpenAI),
+            suffix: command.suffix.clone().or_else(|| file.overrides.suffix.clone()),
             completion,
             file
         })
@@ -88,16 +131,74 @@ impl SessionResultExt for SessionResult {
 
 #[derive(From, Debug)]
 pub enum SessionError {
+    ApiKeyFile(ApiKeyFileError),
+    InvalidApiKey(InvalidApiKeyError),
+    /// best_of has to be at least the response count, since the responses are picked from them
+    InvalidBestOf,
     NoMatchingModel,
+    MaxTokensOutOfValidRange,
     TemperatureOutOfValidRange,
+    TooManyStopSequences,
     ClashingArguments(ClashingArgumentsError),
     CohereError(CohereError),
     OpenAIError(OpenAIError),
+    /// The OpenAI account ran out of credit, this isn't retried like other rate limits
+    #[from(ignore)]
+    QuotaExceeded(OpenAIError),
     IOError(std::io::Error),
     DeserializeError(reqwest::Error),
+    Serde(serde_json::Error),
+    EventSource(Box<reqwest_eventsource::Error>),
+    /// The request can't be streamed, since its body can't be cloned to reconnect with
+    StreamRequest(reqwest_eventsource::CannotCloneRequestError),
+    #[from(ignore)]
+    Request(reqwest::Error),
+    Timeout,
     Unauthorized
 }
 
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            SessionError::OpenAIError(error) => write!(f, "{}", error),
+            SessionError::QuotaExceeded(error) => write!(f,
+                "The OpenAI account is out of credit, check your plan and billing details. {}",
+                error),
+            SessionError::InvalidApiKey(error) => write!(f, "{}", error),
+            SessionError::InvalidBestOf => write!(f,
+                "The best of option has to be at least the response count"),
+            _ => write!(f, "{:#?}", self)
+        }
+    }
+}
+
+impl From<ApiKeyError> for SessionError {
+    fn from(error: ApiKeyError) -> Self {
+        match error {
+            ApiKeyError::File(error) => SessionError::ApiKeyFile(error),
+            ApiKeyError::Invalid(error) => SessionError::InvalidApiKey(error)
+        }
+    }
+}
+
+impl SessionError {
+    pub(crate) fn from_request_error(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            SessionError::Timeout
+        } else {
+            SessionError::Request(error)
+        }
+    }
+
+    pub(crate) fn from_openai_error(error: OpenAIError) -> Self {
+        if error.is_quota_exceeded() {
+            SessionError::QuotaExceeded(error)
+        } else {
+            SessionError::OpenAIError(error)
+        }
+    }
+}
+
 impl SessionCommand {
     #[async_recursion]
     pub async fn run(&self, client: &Client, config: &Config) -> SessionResult {
@@ -111,6 +212,7 @@ impl SessionCommand {
             Provider::Cohere => Err(CohereSessionCommand::try_from(&options)?),
         };
 
+        let streamed = matches!(&command, Ok(command) if command.streams());
         let print_output = !options.completion.quiet.unwrap_or(false);
         if print_output && options.file.transcript.len() > 0 {
             println!("{}", options.file.transcript);
@@ -144,24 +246,38 @@ impl SessionCommand {
                 Err(command) => command.run(client, config, &prompt).await?,
             };
 
+            if options.completion.dry_run.unwrap_or(false) {
+                return Ok(result);
+            }
+
             if let Some(count) = options.completion.response_count {
                 if count > 1 {
                     return Ok(result);
                 }
             }
 
-            let text = result.first().unwrap().trim();
-            let written_response = match &options.completion.prefix_ai {
-                Some(prefix) => format!("{}{}", prefix, text),
-                None => text.to_owned()
+            // An echoed response starts with the prompt, which is already in the transcript, so
+            // only the completion after it is written to the session file
+            let response = result.first().unwrap();
+            let text = match options.echo {
+                true => response.trim_start()
+                    .strip_prefix(prompt.trim_start())
+                    .unwrap_or(response)
+                    .trim(),
+                false => response.trim()
+            };
+            let written_response = match (&options.completion.prefix_ai, options.echo) {
+                (_, true) => response.trim().to_owned(),
+                (Some(prefix), false) => format!("{}{}", prefix, text),
+                (None, false) => text.to_owned()
             };
             let text = options.file.write(text.into(), options.no_context, false)?;
 
-            if !options.completion.quiet.unwrap_or(false) {
+            if !options.completion.quiet.unwrap_or(false) && !streamed {
                 println!("{}", written_response);
             }
 
-            if options.completion.append.is_some() {
+            if options.completion.parse_once_option() {
                 return Ok(vec![ text.to_string() ]);
             }
 
@@ -211,13 +327,13 @@ pub enum Provider {
 
 #[derive(Copy, Clone, Debug, Default, ValueEnum, Serialize, Deserialize)]
 pub enum Model {
-    /// In the range of 0 - 1 billion parameters. OpenAI's Ada, Cohere's "small" option.
+    /// In the range of 0 - 1 billion parameters. Cohere's "small" option.
     Tiny,
 
-    /// In the range of 1 - 5 billion parameters. OpenAI's Babbage option.
+    /// In the range of 1 - 5 billion parameters. OpenAI's babbage-002 option.
     Small,
 
-    /// In the range of 5 - 10 billion parameters. OpenAI's Curie, Cohere's "medium" option.
+    /// In the range of 5 - 10 billion parameters. Cohere's "medium" option.
     Medium,
 
     /// In the range of 10 - 50 billion parameters. Cohere's large option.
@@ -226,7 +342,7 @@ pub enum Model {
     /// In the range of 50 - 150 billion paramaters. Cohere's xlarge option.
     XLarge,
 
-    /// Greater than 150 billion paramaters. OpenAI's davinci model.
+    /// Greater than 150 billion paramaters. OpenAI's gpt-3.5-turbo-instruct model.
     #[default]
     XXLarge
 }
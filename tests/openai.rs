@@ -0,0 +1,701 @@
+use std::sync::{Arc,Mutex};
+use ai::{
+    ChatCommand,
+    ChatError,
+    ChatRole,
+    ChatStreamHandler,
+    CompletionOptions,
+    Config,
+    ImageCommand,
+    ImageData,
+    OpenAIChatModel,
+    list_models,
+    OpenAISessionCommand,
+    SessionCommand,
+    SessionError
+};
+use futures_util::StreamExt;
+use reqwest::Client;
+use serde_json::{json,Value};
+use wiremock::{Mock,MockServer,ResponseTemplate};
+use wiremock::matchers::path;
+
+/// Starts a stand in for the OpenAI API that answers requests to the path with the response
+async fn mock_openai(endpoint: &str, response: ResponseTemplate) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(path(endpoint)).respond_with(response).mount(&server).await;
+    server
+}
+
+fn json_response(status: u16, body: Value) -> ResponseTemplate {
+    ResponseTemplate::new(status).set_body_json(body)
+}
+
+fn event_stream(events: String) -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_raw(events, "text/event-stream")
+}
+
+/// The JSON bodies of the requests the mock received, in order
+async fn received_bodies(server: &MockServer) -> Vec<Value> {
+    server.received_requests()
+        .await
+        .unwrap_or_default()
+        .iter()
+        .map(|request| serde_json::from_slice(&request.body).unwrap_or_default())
+        .collect()
+}
+
+/// A server sent event with a chunk of a streamed chat completion
+fn chat_chunk(delta: Value, finish_reason: Option<&str>) -> String {
+    format!("data: {}\n\n", json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion.chunk",
+        "created": 0,
+        "model": "gpt-4",
+        "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }]
+    }))
+}
+
+fn config(base_url: String, name: &str) -> Config {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!("ai-cli-test-{}-{}", name, std::process::id()));
+
+    Config {
+        base_url: Some(base_url),
+        dir,
+        ..Config::default()
+    }
+}
+
+fn completion(stream: bool) -> CompletionOptions {
+    CompletionOptions {
+        api_key: Some("test-key".into()),
+        append: Some("Hello".into()),
+        stream: Some(stream),
+        ..CompletionOptions::default()
+    }
+}
+
+#[tokio::test]
+async fn chat_returns_the_response() {
+    let server = mock_openai("/v1/chat/completions", json_response(200, json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "gpt-4",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": "Quack" },
+            "finish_reason": "stop"
+        }]
+    }))).await;
+    let config = config(server.uri(), "chat-sync");
+
+    let printed = Arc::new(Mutex::new(String::new()));
+    let handler_printed = printed.clone();
+    let command = ChatCommand {
+        completion: completion(false),
+        system: Some("You're a duck.".into()),
+        return_response: true,
+        stream_handler: Some(ChatStreamHandler::new(move |text| {
+            handler_printed.lock().unwrap().push_str(text);
+            Ok(())
+        })),
+        ..ChatCommand::default()
+    };
+
+    let messages = command.run(&Client::new(), &config).await.unwrap();
+    assert_eq!(1, messages.len());
+    assert_eq!(ChatRole::Ai, messages[0].role);
+    assert_eq!("Quack", messages[0].content.text());
+    assert_eq!(Some("stop"), messages[0].finish_reason.as_deref());
+    assert_eq!("AI: Quack\n", &*printed.lock().unwrap());
+
+    let requests = received_bodies(&server).await;
+    assert_eq!(false, requests[0]["stream"]);
+    assert_eq!("You're a duck.", requests[0]["messages"][0]["content"]);
+    assert_eq!("Hello", requests[0]["messages"][1]["content"]);
+}
+
+#[tokio::test]
+async fn chat_runs_with_a_different_system_message() {
+    let server = mock_openai("/v1/chat/completions", json_response(200, json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "gpt-4",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": "Moo" },
+            "finish_reason": "stop"
+        }]
+    }))).await;
+    let config = config(server.uri(), "chat-system");
+
+    let command = ChatCommand {
+        completion: CompletionOptions { quiet: Some(true), ..completion(false) },
+        system: Some("You're a duck.".into()),
+        return_response: true,
+        ..ChatCommand::default()
+    };
+
+    let messages = command.run_with_system("You're a cow.", &Client::new(), &config).await.unwrap();
+    assert_eq!("Moo", messages[0].content.text());
+    assert_eq!(Some("You're a duck."), command.system.as_deref());
+
+    let requests = received_bodies(&server).await;
+    assert_eq!("You're a cow.", requests[0]["messages"][0]["content"]);
+}
+
+fn chat_choices(choices: &[(&str, &str)]) -> ResponseTemplate {
+    json_response(200, json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "gpt-4",
+        "choices": choices.iter().enumerate().map(|(index, (content, finish_reason))| json!({
+            "index": index,
+            "message": { "role": "assistant", "content": content },
+            "finish_reason": finish_reason
+        })).collect::<Vec<_>>()
+    }))
+}
+
+#[tokio::test]
+async fn chat_prints_and_saves_every_response() {
+    let server = mock_openai("/v1/chat/completions", chat_choices(&[("Quack", "stop"), ("Moo", "stop")])).await;
+    let config = config(server.uri(), "chat-choices");
+
+    let printed = Arc::new(Mutex::new(String::new()));
+    let handler_printed = printed.clone();
+    let command = ChatCommand {
+        completion: CompletionOptions {
+            name: Some(String::from("duck")),
+            overwrite: Some(true),
+            response_count: Some(2),
+            ..completion(false)
+        },
+        stream_handler: Some(ChatStreamHandler::new(move |text| {
+            handler_printed.lock().unwrap().push_str(text);
+            Ok(())
+        })),
+        ..ChatCommand::default()
+    };
+
+    let messages = command.run(&Client::new(), &config).await.unwrap();
+    assert_eq!(vec!["Quack", "Moo"], messages.iter().map(|m| m.content.text()).collect::<Vec<_>>());
+    assert_eq!("Response 1 of 2:\nAI: Quack\nResponse 2 of 2:\nAI: Moo\n", &*printed.lock().unwrap());
+
+    let session = std::fs::read_to_string(config.dir.join("sessions").join("duck")).unwrap();
+    assert!(session.ends_with("USER: Hello\nAI: Quack\nAI: Moo\n"));
+
+    let requests = received_bodies(&server).await;
+    assert_eq!(2, requests[0]["n"]);
+}
+
+#[tokio::test]
+async fn chat_rejects_the_responses_when_one_is_filtered() {
+    let server = mock_openai(
+        "/v1/chat/completions",
+        chat_choices(&[("Quack", "stop"), ("", "content_filter")])).await;
+    let config = config(server.uri(), "chat-choices-filtered");
+
+    let command = ChatCommand {
+        completion: CompletionOptions {
+            name: Some(String::from("duck")),
+            overwrite: Some(true),
+            quiet: Some(true),
+            response_count: Some(2),
+            ..completion(false)
+        },
+        ..ChatCommand::default()
+    };
+
+    let result = command.run(&Client::new(), &config).await;
+    assert!(matches!(result, Err(ChatError::ContentFiltered)));
+
+    let session = std::fs::read_to_string(config.dir.join("sessions").join("duck")).unwrap();
+    assert!(!session.contains("Quack"));
+}
+
+#[tokio::test]
+async fn chat_streams_the_response() {
+    let events = [
+        chat_chunk(json!({ "role": "assistant", "content": "" }), None),
+        chat_chunk(json!({ "content": "Qua" }), None),
+        chat_chunk(json!({ "content": "ck" }), None),
+        chat_chunk(json!({}), Some("length")),
+        String::from("data: [DONE]\n\n")
+    ].concat();
+    let server = mock_openai("/v1/chat/completions", event_stream(events)).await;
+    let config = config(server.uri(), "chat-stream");
+
+    let streamed = Arc::new(Mutex::new(String::new()));
+    let handler_streamed = streamed.clone();
+    let command = ChatCommand {
+        completion: completion(true),
+        return_response: true,
+        stream_handler: Some(ChatStreamHandler::new(move |text| {
+            handler_streamed.lock().unwrap().push_str(text);
+            Ok(())
+        })),
+        ..ChatCommand::default()
+    };
+
+    let messages = command.run(&Client::new(), &config).await.unwrap();
+    assert_eq!("Quack", messages[0].content.text());
+    assert_eq!(Some("length"), messages[0].finish_reason.as_deref());
+    assert_eq!("AI: Quack\n", &*streamed.lock().unwrap());
+
+    let requests = received_bodies(&server).await;
+    assert_eq!(true, requests[0]["stream"]);
+}
+
+#[tokio::test]
+async fn streamed_and_waited_for_responses_are_saved_the_same_way() {
+    let sync_server = mock_openai("/v1/chat/completions", json_response(200, json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "gpt-4",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": "AI: Quack" },
+            "finish_reason": "stop"
+        }]
+    }))).await;
+    let events = [
+        chat_chunk(json!({ "role": "assistant", "content": "" }), None),
+        chat_chunk(json!({ "content": "AI: Qua" }), None),
+        chat_chunk(json!({ "content": "ck" }), None),
+        chat_chunk(json!({}), Some("stop")),
+        String::from("data: [DONE]\n\n")
+    ].concat();
+    let stream_server = mock_openai("/v1/chat/completions", event_stream(events)).await;
+
+    let transcript = |server: &MockServer, stream: bool| {
+        let config = config(server.uri(), &format!("chat-transcript-{}", stream));
+        let command = ChatCommand {
+            completion: CompletionOptions {
+                name: Some(String::from("duck")),
+                overwrite: Some(true),
+                quiet: Some(true),
+                ..completion(stream)
+            },
+            return_response: true,
+            ..ChatCommand::default()
+        };
+
+        async move {
+            command.run(&Client::new(), &config).await.unwrap();
+            let session = std::fs::read_to_string(config.dir.join("sessions").join("duck")).unwrap();
+            session.lines().skip_while(|line| !line.starts_with("USER:")).collect::<Vec<_>>().join("\n")
+        }
+    };
+
+    let waited_for = transcript(&sync_server, false).await;
+    assert_eq!("USER: Hello\nAI: Quack", waited_for);
+    assert_eq!(waited_for, transcript(&stream_server, true).await);
+}
+
+#[tokio::test]
+async fn chat_stream_yields_the_content_deltas() {
+    let events = [
+        chat_chunk(json!({ "role": "assistant", "content": "" }), None),
+        chat_chunk(json!({ "content": "AI: Qua" }), None),
+        chat_chunk(json!({ "content": "ck" }), None),
+        chat_chunk(json!({}), Some("stop")),
+        String::from("data: [DONE]\n\n")
+    ].concat();
+    let server = mock_openai("/v1/chat/completions", event_stream(events)).await;
+    let config = config(server.uri(), "chat-content-stream");
+
+    let command = ChatCommand {
+        completion: completion(true),
+        ..ChatCommand::default()
+    };
+    let deltas: Vec<String> = command.stream(&Client::new(), &config)
+        .await
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    assert_eq!(vec!["Qua", "ck"], deltas);
+
+    let requests = received_bodies(&server).await;
+    assert_eq!(true, requests[0]["stream"]);
+    assert_eq!("Hello", requests[0]["messages"][1]["content"]);
+}
+
+#[tokio::test]
+async fn chat_stream_ends_with_the_first_error() {
+    let events = [
+        chat_chunk(json!({ "role": "assistant", "content": "Qua" }), None),
+        format!("data: {}\n\n", json!({
+            "error": {
+                "message": "The server had an error",
+                "type": "server_error",
+                "param": null,
+                "code": null
+            }
+        })),
+        chat_chunk(json!({ "content": "ck" }), None)
+    ].concat();
+    let server = mock_openai("/v1/chat/completions", event_stream(events)).await;
+    let config = config(server.uri(), "chat-content-stream-error");
+
+    let command = ChatCommand {
+        completion: completion(true),
+        ..ChatCommand::default()
+    };
+    let deltas: Vec<_> = command.stream(&Client::new(), &config).await.unwrap().collect().await;
+    assert_eq!(2, deltas.len());
+    assert_eq!("Qua", deltas[0].as_ref().unwrap());
+    assert!(matches!(deltas[1], Err(ChatError::OpenAIError(_))));
+}
+
+#[tokio::test]
+async fn chat_stream_reports_unsuccessful_responses() {
+    let server = mock_openai("/v1/chat/completions", ResponseTemplate::new(500)).await;
+    let config = config(server.uri(), "chat-content-stream-status");
+
+    let command = ChatCommand {
+        completion: completion(true),
+        ..ChatCommand::default()
+    };
+    let deltas: Vec<_> = command.stream(&Client::new(), &config).await.unwrap().collect().await;
+    assert_eq!(1, deltas.len());
+    assert!(matches!(deltas[0], Err(ChatError::EventSource(_))));
+}
+
+#[tokio::test]
+async fn chat_verify_lists_the_models() {
+    let server = mock_openai("/v1/models", json_response(200, json!({
+        "object": "list",
+        "data": [{ "id": "gpt-4", "object": "model", "created": 0, "owned_by": "openai" }]
+    }))).await;
+    let config = config(server.uri(), "chat-verify");
+
+    let command = ChatCommand {
+        completion: completion(false),
+        ..ChatCommand::default()
+    };
+    command.verify(&Client::new(), &config).await.unwrap();
+
+    assert_eq!(1, received_bodies(&server).await.len());
+}
+
+#[tokio::test]
+async fn chat_verify_reports_a_rejected_api_key() {
+    let server = mock_openai("/v1/models", json_response(401, json!({
+        "error": {
+            "message": "Incorrect API key provided.",
+            "type": "invalid_request_error",
+            "param": null,
+            "code": "invalid_api_key"
+        }
+    }))).await;
+    let config = config(server.uri(), "chat-verify-rejected");
+
+    let command = ChatCommand {
+        completion: completion(false),
+        ..ChatCommand::default()
+    };
+    let result = command.verify(&Client::new(), &config).await;
+    assert!(matches!(result, Err(ChatError::ApiKeyRejected)));
+}
+
+#[tokio::test]
+async fn chat_verify_needs_an_api_key() {
+    std::env::remove_var("OPENAI_API_KEY");
+    std::env::remove_var("OPEN_AI_API_KEY");
+    let server = mock_openai("/v1/models", json_response(200, json!({
+        "object": "list",
+        "data": []
+    }))).await;
+    let config = config(server.uri(), "chat-verify-no-key");
+
+    let command = ChatCommand {
+        completion: CompletionOptions { api_key: None, ..completion(false) },
+        ..ChatCommand::default()
+    };
+    let result = command.verify(&Client::new(), &config).await;
+    assert!(matches!(result, Err(ChatError::Unauthorized)));
+    assert!(received_bodies(&server).await.is_empty());
+}
+
+#[tokio::test]
+async fn images_are_generated() {
+    let server = mock_openai("/v1/images/generations", json_response(200, json!({
+        "created": 0,
+        "data": [{ "url": "https://example.com/duck.png" }]
+    }))).await;
+    let config = Config {
+        api_key_openai: Some("test-key".into()),
+        ..config(server.uri(), "image")
+    };
+
+    let command = ImageCommand {
+        prompt: String::from("A duck"),
+        ..ImageCommand::default()
+    };
+    let images = command.run(&Client::new(), &config).await.unwrap();
+    assert!(matches!(&images[..], [ImageData::Url(image)] if image.url == "https://example.com/duck.png"));
+
+    let requests = received_bodies(&server).await;
+    assert_eq!("A duck", requests[0]["prompt"]);
+    assert_eq!("512x512", requests[0]["size"]);
+    assert_eq!("url", requests[0]["response_format"]);
+}
+
+#[tokio::test]
+async fn models_are_listed_by_id() {
+    let model = |id| json!({
+        "id": id, "object": "model", "created": 0, "owned_by": "openai"
+    });
+    let server = mock_openai("/v1/models", json_response(200, json!({
+        "object": "list",
+        "data": [model("gpt-4o"), model("gpt-3.5-turbo"), model("dall-e-3")]
+    }))).await;
+    let config = config(server.uri(), "models");
+
+    let models = list_models(&Client::new(), &config, Some("test-key")).await.unwrap();
+    assert_eq!(vec!["dall-e-3", "gpt-3.5-turbo", "gpt-4o"], models);
+
+    assert_eq!(1, received_bodies(&server).await.len());
+}
+
+#[tokio::test]
+async fn session_completes_the_prompt() {
+    let server = mock_openai("/v1/completions", json_response(200, json!({
+        "id": "cmpl-1",
+        "object": "text_completion",
+        "created": 0,
+        "model": "gpt-3.5-turbo-instruct",
+        "choices": [{ "text": " quack", "index": 0, "logprobs": null, "finish_reason": "stop" }]
+    }))).await;
+    let config = config(server.uri(), "session");
+
+    let command = SessionCommand {
+        completion: CompletionOptions {
+            append: None,
+            quiet: Some(true),
+            ..completion(false)
+        },
+        ..SessionCommand::default()
+    };
+    let session = OpenAISessionCommand::try_from((&command, &config)).unwrap();
+
+    let result = session.run(&Client::new(), &config, "The duck says").await.unwrap();
+    assert_eq!(vec![String::from(" quack")], result);
+
+    let requests = received_bodies(&server).await;
+    assert_eq!("The duck says", requests[0]["prompt"]);
+}
+
+#[tokio::test]
+async fn session_completes_the_prompt_with_a_chat_model() {
+    let server = mock_openai("/v1/chat/completions", json_response(200, json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "gpt-4o",
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": "quack" },
+            "finish_reason": "stop"
+        }]
+    }))).await;
+    let config = config(server.uri(), "session-chat");
+
+    let command = SessionCommand {
+        chat_model: Some(OpenAIChatModel::Gpt4o),
+        completion: CompletionOptions {
+            append: None,
+            quiet: Some(true),
+            ..completion(false)
+        },
+        ..SessionCommand::default()
+    };
+    let session = OpenAISessionCommand::try_from((&command, &config)).unwrap();
+
+    let result = session.run(&Client::new(), &config, "The duck says").await.unwrap();
+    assert_eq!(vec![String::from("quack")], result);
+
+    let requests = received_bodies(&server).await;
+    assert_eq!("user", requests[0]["messages"][0]["role"]);
+    assert_eq!("The duck says", requests[0]["messages"][0]["content"]);
+    assert!(requests[0]["prompt"].is_null());
+}
+
+#[tokio::test]
+async fn echoed_prompts_are_written_to_the_session_once() {
+    let server = mock_openai("/v1/completions", json_response(200, json!({
+        "id": "cmpl-1",
+        "object": "text_completion",
+        "created": 0,
+        "model": "gpt-3.5-turbo-instruct",
+        "choices": [{ "text": "Hello\n quack", "index": 0, "logprobs": null, "finish_reason": "stop" }]
+    }))).await;
+    let config = config(server.uri(), "session-echo");
+
+    let command = SessionCommand {
+        completion: CompletionOptions {
+            name: Some(String::from("duck")),
+            overwrite: Some(true),
+            quiet: Some(true),
+            ..completion(false)
+        },
+        echo: Some(true),
+        prompt: Some(String::from("${TRANSCRIPT}")),
+        ..SessionCommand::default()
+    };
+
+    let result = command.run(&Client::new(), &config).await.unwrap();
+    assert_eq!(vec![String::from("quack")], result);
+
+    let session = std::fs::read_to_string(config.dir.join("sessions").join("duck")).unwrap();
+    let transcript = session.split("<->\n").nth(1).unwrap();
+    assert_eq!("Hello\nquack\n", transcript);
+}
+
+fn quota_exceeded() -> ResponseTemplate {
+    json_response(429, json!({
+        "error": {
+            "message": "You exceeded your current quota.",
+            "type": "insufficient_quota",
+            "param": null,
+            "code": "insufficient_quota"
+        }
+    }))
+}
+
+#[tokio::test]
+async fn exhausted_quotas_are_not_retried() {
+    let server = mock_openai("/v1/chat/completions", quota_exceeded()).await;
+    let config = config(server.uri(), "chat-quota");
+
+    let command = ChatCommand {
+        completion: completion(false),
+        ..ChatCommand::default()
+    };
+    let result = command.run(&Client::new(), &config).await;
+    assert!(matches!(result, Err(ChatError::QuotaExceeded(_))));
+    assert_eq!(1, received_bodies(&server).await.len());
+    assert_eq!(0, config.stats.retries());
+}
+
+#[tokio::test]
+async fn rate_limits_are_retried() {
+    let rate_limited = json_response(429, json!({
+        "error": {
+            "message": "Rate limit reached.",
+            "type": "requests",
+            "param": null,
+            "code": "rate_limit_exceeded"
+        }
+    })).insert_header("Retry-After", "0");
+    let server = mock_openai("/v1/chat/completions", rate_limited).await;
+    let config = Config {
+        max_retries: Some(1),
+        ..config(server.uri(), "chat-rate-limit")
+    };
+
+    let command = ChatCommand {
+        completion: completion(false),
+        ..ChatCommand::default()
+    };
+    let result = command.run(&Client::new(), &config).await;
+    assert!(matches!(result, Err(ChatError::OpenAIError(_))));
+    assert_eq!(2, received_bodies(&server).await.len());
+    assert_eq!(1, config.stats.retries());
+}
+
+#[tokio::test]
+async fn exhausted_quotas_are_reported_when_streaming() {
+    let server = mock_openai("/v1/chat/completions", quota_exceeded()).await;
+    let config = config(server.uri(), "chat-stream-quota");
+
+    let command = ChatCommand {
+        completion: completion(true),
+        ..ChatCommand::default()
+    };
+    let result = command.run(&Client::new(), &config).await;
+    assert!(matches!(result, Err(ChatError::QuotaExceeded(_))));
+}
+
+#[tokio::test]
+async fn exhausted_quotas_are_reported_for_sessions() {
+    let server = mock_openai("/v1/completions", quota_exceeded()).await;
+    let config = config(server.uri(), "session-quota");
+
+    let command = SessionCommand {
+        completion: CompletionOptions {
+            append: None,
+            quiet: Some(true),
+            ..completion(false)
+        },
+        ..SessionCommand::default()
+    };
+    let session = OpenAISessionCommand::try_from((&command, &config)).unwrap();
+
+    let result = session.run(&Client::new(), &config, "The duck says").await;
+    assert!(matches!(result, Err(SessionError::QuotaExceeded(_))));
+    assert_eq!(1, received_bodies(&server).await.len());
+}
+
+#[test]
+fn session_best_of_has_to_cover_the_response_count() {
+    let config = config(String::from("http://127.0.0.1:1"), "session-best-of");
+    let command = |best_of| SessionCommand {
+        best_of: Some(best_of),
+        completion: CompletionOptions {
+            response_count: Some(2),
+            ..CompletionOptions::default()
+        },
+        ..SessionCommand::default()
+    };
+
+    assert!(matches!(
+        OpenAISessionCommand::try_from((&command(1), &config)),
+        Err(SessionError::InvalidBestOf)));
+    assert!(OpenAISessionCommand::try_from((&command(2), &config)).is_ok());
+}
+
+#[tokio::test]
+async fn chat_and_session_reject_a_response_count_of_zero_the_same_way() {
+    let config = config(String::from("http://127.0.0.1:1"), "response-count");
+
+    let chat = ChatCommand {
+        completion: CompletionOptions { response_count: Some(0), ..completion(false) },
+        ..ChatCommand::default()
+    };
+    let chat_error = match chat.run(&Client::new(), &config).await {
+        Err(ChatError::ClashingArguments(error)) => error.error,
+        result => panic!("Expected the response count to be rejected, got {:?}", result)
+    };
+
+    let session = SessionCommand {
+        completion: CompletionOptions { response_count: Some(0), ..CompletionOptions::default() },
+        ..SessionCommand::default()
+    };
+    let session_error = match OpenAISessionCommand::try_from((&session, &config)) {
+        Err(SessionError::ClashingArguments(error)) => error.error,
+        Err(error) => panic!("Expected the response count to be rejected, got {:?}", error),
+        Ok(_) => panic!("Expected the response count to be rejected")
+    };
+
+    assert_eq!(chat_error, session_error);
+}
+
+#[test]
+fn openai_paths_use_the_api_path_prefix() {
+    let config = |prefix: Option<&str>| Config {
+        api_path_prefix: prefix.map(String::from),
+        ..Config::default()
+    };
+
+    assert_eq!("v1/chat/completions", config(None).openai_api_path("chat/completions"));
+    assert_eq!("openai/v2/completions", config(Some("/openai/v2/")).openai_api_path("completions"));
+    assert_eq!("completions", config(Some("")).openai_api_path("completions"));
+}